@@ -0,0 +1,128 @@
+//! Throughput of publishing messages from many concurrent clients, each
+//! waiting for a message to be stored before publishing the next one.
+//!
+//! Runs against the server binary started on a free local port, with fewer
+//! workers than publishers and as many connections allowed as the workers
+//! and their queue take, which is the largest number of publishers, so that
+//! the server is at its limits.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Messages published by each client in a single iteration
+const MESSAGES: usize = 100;
+
+/// Size of each published message
+const MESSAGE_SIZE: usize = 64;
+
+/// Server process, killed when dropped
+struct Server {
+    process: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Self {
+        // find a free port by letting the OS pick one
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .unwrap()
+            .to_string();
+        let process = Command::new(env!("CARGO_BIN_EXE_tcp-mailbox"))
+            .args(["--listen", &addr])
+            .args(["--workers", "16", "--worker-queue", "16"])
+            .args(["--max-connections", "32"])
+            .stdout(Stdio::null())
+            // logs warnings about busy workers
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start server");
+        let server = Server { process, addr };
+
+        let started = Instant::now();
+        while TcpStream::connect(&server.addr).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server did not start"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        server
+    }
+
+    fn connect(&self) -> (BufReader<TcpStream>, TcpStream) {
+        let stream = TcpStream::connect(&self.addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(
+            greeting.starts_with("OK"),
+            "unexpected greeting {:?}",
+            greeting
+        );
+        (reader, stream)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Publish messages to a mailbox one at a time
+fn publish(reader: &mut BufReader<TcpStream>, writer: &mut TcpStream, mailbox: &str) {
+    let request = format!(
+        "PUB {} {}\n{}\n",
+        mailbox,
+        MESSAGE_SIZE,
+        "x".repeat(MESSAGE_SIZE)
+    );
+    let mut response = String::new();
+    for _ in 0..MESSAGES {
+        writer.write_all(request.as_bytes()).unwrap();
+        response.clear();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("OK"), "publish failed: {:?}", response);
+    }
+}
+
+fn concurrent_publishers(c: &mut Criterion) {
+    let server = Server::start();
+    let mut group = c.benchmark_group("publish");
+    for publishers in [1, 8, 32] {
+        group.throughput(Throughput::Elements((publishers * MESSAGES) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(publishers),
+            &publishers,
+            |b, &publishers| {
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        // connections are set up before timing starts
+                        let clients: Vec<_> = (0..publishers).map(|_| server.connect()).collect();
+                        let started = Instant::now();
+                        thread::scope(|scope| {
+                            for (i, (mut reader, mut writer)) in clients.into_iter().enumerate() {
+                                scope.spawn(move || {
+                                    publish(&mut reader, &mut writer, &format!("bench-{}", i))
+                                });
+                            }
+                        });
+                        elapsed += started.elapsed();
+                    }
+                    elapsed
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_publishers);
+criterion_main!(benches);
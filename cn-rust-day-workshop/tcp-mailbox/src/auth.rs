@@ -0,0 +1,388 @@
+//! Authentication of clients and access control to mailboxes
+//!
+//! Clients authenticate with a shared token or a user name and password,
+//! both configured in the config file, and are then known by the name of the
+//! token or user, their _principal_. What principals may do is configured as
+//! a list of rules, each allowing some operations on mailboxes matching a
+//! pattern; anything not allowed by a rule is denied.
+
+use ring::digest::{self, SHA256};
+use serde::Deserialize;
+use std::fmt;
+
+use crate::protocol::{Credentials, ErrorCode, Request};
+
+/// Rules with this principal apply to every authenticated client
+const ANY_PRINCIPAL: &str = "*";
+
+/// Operations on a mailbox that can be allowed
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// `PUB` and `MPUB` to a mailbox and `CAST` to a topic
+    Publish,
+    /// `GET`, `ACK` and `NACK` on a mailbox and `SUB` to a topic
+    Retrieve,
+    /// administration of a mailbox: seeing it in `LIST` and `STATS`, and
+    /// `PEEK`, `COUNT`, `PURGE` and `DEL` on it
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permission::Publish => write!(f, "publish"),
+            Permission::Retrieve => write!(f, "retrieve"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// A password or token, kept out of debug output
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Compare in constant time, so that timing doesn't give away how much
+    /// of a guess was right
+    ///
+    /// Digests are compared rather than the secret itself, as they're of the
+    /// same length whatever the guess, so that not even the length of the
+    /// secret is given away.
+    pub fn matches(&self, guess: &str) -> bool {
+        let secret = digest::digest(&SHA256, self.0.as_bytes());
+        let guess = digest::digest(&SHA256, guess.as_bytes());
+        secret
+            .as_ref()
+            .iter()
+            .zip(guess.as_ref())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+    pub password: Secret,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    /// principal the token identifies
+    pub name: String,
+    pub token: Secret,
+}
+
+/// Operations a principal is allowed on mailboxes matching a pattern
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// name of a user or token, or `*` for any of them
+    pub principal: String,
+    /// mailbox names, where `*` matches any number of characters
+    pub mailboxes: String,
+    pub allow: Vec<Permission>,
+}
+
+/// Credentials clients can authenticate with and what they're allowed to
+/// do; when no credentials are configured, anyone may do anything
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub users: Vec<User>,
+    pub tokens: Vec<Token>,
+    pub acl: Vec<Rule>,
+}
+
+/// Why a request was refused
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Denied {
+    /// client has to authenticate first
+    Unauthenticated,
+    /// client isn't allowed the operation on the mailbox
+    Forbidden {
+        permission: Permission,
+        mailbox: String,
+    },
+}
+
+impl Denied {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Denied::Unauthenticated => ErrorCode::Unauthorized,
+            Denied::Forbidden { .. } => ErrorCode::Forbidden,
+        }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Denied::Unauthenticated => write!(f, "authentication required"),
+            Denied::Forbidden {
+                permission,
+                mailbox,
+            } => write!(f, "permission denied: {} on {}", permission, mailbox),
+        }
+    }
+}
+
+impl Auth {
+    /// Whether clients have to authenticate
+    pub fn is_enabled(&self) -> bool {
+        !self.users.is_empty() || !self.tokens.is_empty()
+    }
+
+    /// Check credentials can be sent in `AUTH`, i.e. don't contain
+    /// whitespace, returns the reason if they can't
+    pub fn validate(&self) -> Result<(), String> {
+        let has_space = |s: &str| s.chars().any(char::is_whitespace);
+        for user in &self.users {
+            if has_space(&user.name) || has_space(&user.password.0) {
+                return Err(format!(
+                    "name or password of user {:?} contains whitespace",
+                    user.name
+                ));
+            }
+        }
+        for token in &self.tokens {
+            if has_space(&token.token.0) {
+                return Err(format!("token {:?} contains whitespace", token.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check credentials, returns the principal they identify if valid
+    ///
+    /// Credentials are checked against every token or user rather than
+    /// stopping at the first match, so that timing doesn't give away which
+    /// one matched, nor whether a user exists.
+    pub fn authenticate(&self, credentials: &Credentials) -> Option<&str> {
+        match credentials {
+            Credentials::Token(token) => self
+                .tokens
+                .iter()
+                .filter(|t| t.token.matches(token))
+                .fold(None, |found, t| found.or(Some(t.name.as_str()))),
+            Credentials::Password { user, password } => self
+                .users
+                .iter()
+                .filter(|u| (u.name == *user) & u.password.matches(password))
+                .fold(None, |found, u| found.or(Some(u.name.as_str()))),
+        }
+    }
+
+    /// Whether a principal, None meaning an unauthenticated client, is
+    /// allowed an operation on a mailbox
+    pub fn is_allowed(
+        &self,
+        principal: Option<&str>,
+        permission: Permission,
+        mailbox: &str,
+    ) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let principal = match principal {
+            Some(principal) => principal,
+            None => return false,
+        };
+        self.acl.iter().any(|rule| {
+            (rule.principal == ANY_PRINCIPAL || rule.principal == principal)
+                && rule.allow.contains(&permission)
+                && matches(&rule.mailboxes, mailbox)
+        })
+    }
+
+    /// Check whether a client may make a request
+    ///
+    /// Unauthenticated clients may only `PING`, `AUTH` and `QUIT`. `LIST` and
+    /// `STATS` are allowed to everyone authenticated, reporting only on
+    /// mailboxes they may administer.
+    pub fn check(&self, principal: Option<&str>, request: &Request) -> Result<(), Denied> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let (permission, mailbox) = match request {
+            Request::Ping | Request::Auth(_) | Request::Quit | Request::Unsubscribe => {
+                return Ok(())
+            }
+            _ if principal.is_none() => return Err(Denied::Unauthenticated),
+            Request::List | Request::Stats => return Ok(()),
+            Request::Publish { mailbox, .. }
+            | Request::PublishBatch { mailbox, .. }
+            | Request::Cast { topic: mailbox, .. } => (Permission::Publish, mailbox),
+            Request::Retrieve { mailbox, .. }
+            | Request::Ack { mailbox, .. }
+            | Request::Nack { mailbox, .. }
+            | Request::Subscribe { topic: mailbox, .. } => (Permission::Retrieve, mailbox),
+            Request::Peek { mailbox, .. }
+            | Request::Count { mailbox }
+            | Request::Purge { mailbox }
+            | Request::Delete { mailbox, .. } => (Permission::Admin, mailbox),
+        };
+        if self.is_allowed(principal, permission, mailbox) {
+            Ok(())
+        } else {
+            Err(Denied::Forbidden {
+                permission,
+                mailbox: mailbox.clone(),
+            })
+        }
+    }
+}
+
+/// Match a name against a pattern where `*` matches any number of
+/// characters
+fn matches(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was seen and the part of the name it matches ends,
+    // to backtrack to when the rest of the pattern doesn't match
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the `*` match one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::PublishOptions;
+
+    fn auth() -> Auth {
+        toml::from_str(
+            r#"
+            users = [{ name = "alice", password = "wonderland" }]
+            tokens = [{ name = "ci", token = "s3cret" }]
+
+            [[acl]]
+            principal = "alice"
+            mailboxes = "orders*"
+            allow = ["publish", "retrieve"]
+
+            [[acl]]
+            principal = "*"
+            mailboxes = "public.*"
+            allow = ["retrieve"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn publish(mailbox: &str) -> Request {
+        Request::Publish {
+            mailbox: String::from(mailbox),
+            message: Vec::new(),
+            options: PublishOptions::default(),
+        }
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(matches("orders", "orders"));
+        assert!(!matches("orders", "orders.dead"));
+        assert!(matches("orders*", "orders.dead"));
+        assert!(matches("*.dead", "orders.dead"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn authenticates_with_token_or_password() {
+        let auth = auth();
+        let token = |token: &str| Credentials::Token(String::from(token));
+        let password = |user: &str, password: &str| Credentials::Password {
+            user: String::from(user),
+            password: String::from(password),
+        };
+        assert_eq!(auth.authenticate(&token("s3cret")), Some("ci"));
+        assert_eq!(auth.authenticate(&token("s3cre")), None);
+        assert_eq!(auth.authenticate(&token("s3cret!")), None);
+        assert_eq!(
+            auth.authenticate(&password("alice", "wonderland")),
+            Some("alice")
+        );
+        assert_eq!(auth.authenticate(&password("alice", "s3cret")), None);
+        assert_eq!(auth.authenticate(&password("ci", "s3cret")), None);
+    }
+
+    #[test]
+    fn allows_only_what_rules_allow() {
+        let auth = auth();
+        assert_eq!(auth.check(Some("alice"), &publish("orders")), Ok(()));
+        assert_eq!(
+            auth.check(Some("ci"), &publish("orders")),
+            Err(Denied::Forbidden {
+                permission: Permission::Publish,
+                mailbox: String::from("orders"),
+            })
+        );
+        assert!(auth.is_allowed(Some("ci"), Permission::Retrieve, "public.news"));
+        assert!(!auth.is_allowed(Some("alice"), Permission::Admin, "orders"));
+        let purge = Request::Purge {
+            mailbox: String::from("orders"),
+        };
+        assert_eq!(
+            auth.check(Some("alice"), &purge),
+            Err(Denied::Forbidden {
+                permission: Permission::Admin,
+                mailbox: String::from("orders"),
+            })
+        );
+
+        assert_eq!(auth.check(None, &Request::Ping), Ok(()));
+        assert_eq!(
+            auth.check(None, &publish("orders")),
+            Err(Denied::Unauthenticated)
+        );
+        assert_eq!(auth.check(Some("ci"), &Request::List), Ok(()));
+    }
+
+    #[test]
+    fn allows_anything_without_credentials() {
+        let auth = Auth::default();
+        assert!(!auth.is_enabled());
+        assert_eq!(auth.check(None, &publish("orders")), Ok(()));
+    }
+
+    #[test]
+    fn refuses_credentials_with_whitespace() {
+        assert!(auth().validate().is_ok());
+        let auth: Auth =
+            toml::from_str(r#"tokens = [{ name = "ci", token = "two words" }]"#).unwrap();
+        assert!(auth.validate().is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Command line client for the mailbox server, for publishing messages from
+//! scripts, consuming them in a loop and managing mailboxes
+
+use std::env;
+use std::error::Error;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use structopt::StructOpt;
+
+use tcp_mailbox::client::{Client, ClientError, ClientOptions};
+use tcp_mailbox::protocol::{Credentials, Wait};
+use tcp_mailbox::storage::PublishOptions;
+use tcp_mailbox::tls;
+
+/// Publish messages to, consume messages from and manage mailboxes of a
+/// mailbox server
+#[derive(Debug, StructOpt)]
+struct Cli {
+    /// Address of the server
+    #[structopt(long, env = "MAILBOX_SERVER", default_value = "127.0.0.1:7878")]
+    server: String,
+
+    /// Seconds to wait for the server to respond
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+
+    /// How many times to reconnect and retry a request when the connection
+    /// fails
+    #[structopt(long, default_value = "3")]
+    retries: u32,
+
+    /// Connect over TLS, verifying the server's certificate against the CA
+    /// certificates in this PEM file
+    #[structopt(long, env = "MAILBOX_TLS_CA", parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    /// Present the certificate chain in this PEM file to servers requiring
+    /// client certificates
+    #[structopt(
+        long,
+        env = "MAILBOX_TLS_CERT",
+        parse(from_os_str),
+        requires_all = &["tls-ca", "tls-key"]
+    )]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the client certificate
+    #[structopt(
+        long,
+        env = "MAILBOX_TLS_KEY",
+        parse(from_os_str),
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+
+    /// Authenticate with this token; better given in the environment, where
+    /// other users can't see it
+    #[structopt(
+        long,
+        env = "MAILBOX_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
+
+    /// Authenticate as this user, with the password in MAILBOX_PASSWORD
+    #[structopt(long, env = "MAILBOX_USER")]
+    user: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Publish a message, read from standard input unless given
+    Publish {
+        mailbox: String,
+        message: Option<String>,
+
+        /// Publish each line of standard input as a separate message
+        #[structopt(long, conflicts_with = "message")]
+        lines: bool,
+
+        /// Milliseconds the message may wait to be consumed before it
+        /// expires
+        #[structopt(long)]
+        ttl: Option<u64>,
+
+        /// Priority of the message, from 0 to 255; messages with higher
+        /// priority are consumed first
+        #[structopt(long, default_value = "0")]
+        priority: u8,
+
+        /// Milliseconds to wait before the message can be consumed
+        #[structopt(long)]
+        delay: Option<u64>,
+
+        /// Header to give the message, as NAME=VALUE; can be repeated
+        #[structopt(long = "header", parse(try_from_str = parse_header), number_of_values = 1)]
+        headers: Vec<(String, String)>,
+    },
+    /// Print messages from a mailbox as they arrive, one per line,
+    /// acknowledging each once printed
+    Consume {
+        mailbox: String,
+
+        /// Stop after this many messages
+        #[structopt(long)]
+        count: Option<u64>,
+
+        /// Stop once the mailbox is empty instead of waiting for more
+        /// messages
+        #[structopt(long)]
+        no_wait: bool,
+    },
+    /// Print messages waiting in a mailbox, one per line, without consuming
+    /// them
+    Peek {
+        mailbox: String,
+
+        /// Print up to this many messages
+        #[structopt(long, default_value = "10")]
+        count: usize,
+    },
+    /// Print the number of messages waiting in a mailbox and their size in
+    /// bytes
+    Count { mailbox: String },
+    /// Remove every message from a mailbox, including those being consumed
+    Purge { mailbox: String },
+    /// Remove a message from a mailbox by ID
+    Delete { mailbox: String, id: u64 },
+}
+
+fn main() {
+    let args = Cli::from_args();
+    if let Err(e) = run(args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Cli) -> Result<(), Box<dyn Error>> {
+    let identity = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        _ => None,
+    };
+    let tls = match &args.tls_ca {
+        Some(ca) => Some(tls::client_config(ca, identity)?),
+        None => None,
+    };
+    let credentials = match (args.token, args.user) {
+        (Some(token), _) => Some(Credentials::Token(token)),
+        (None, Some(user)) => {
+            let password = env::var("MAILBOX_PASSWORD")
+                .map_err(|_| "MAILBOX_PASSWORD has to be set along with --user")?;
+            Some(Credentials::Password { user, password })
+        }
+        (None, None) => None,
+    };
+    let options = ClientOptions {
+        timeout: Some(Duration::from_secs(args.timeout)),
+        retries: args.retries,
+        tls,
+        credentials,
+        ..ClientOptions::default()
+    };
+    let mut client = Client::with_options(&args.server, options)?;
+    let result = match args.command {
+        Command::Publish {
+            mailbox,
+            message,
+            lines,
+            ttl,
+            priority,
+            delay,
+            headers,
+        } => {
+            let options = PublishOptions {
+                ttl: ttl.map(Duration::from_millis),
+                priority,
+                delay: delay.map(Duration::from_millis),
+                headers,
+            };
+            publish(&mut client, &mailbox, message, lines, options)
+        }
+        Command::Consume {
+            mailbox,
+            count,
+            no_wait,
+        } => consume(&mut client, &mailbox, count, no_wait),
+        Command::Peek { mailbox, count } => peek(&mut client, &mailbox, count),
+        Command::Count { mailbox } => client.count(&mailbox).map(|(messages, bytes)| {
+            println!("{} messages, {} bytes", messages, bytes);
+        }),
+        Command::Purge { mailbox } => client.purge(&mailbox).map(|purged| {
+            println!("{}", purged);
+        }),
+        Command::Delete { mailbox, id } => client.delete(&mailbox, id),
+    };
+    Ok(result?)
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once('=') {
+        Some((name, value)) => Ok((String::from(name), String::from(value))),
+        None => Err(format!("expected NAME=VALUE, got {:?}", header)),
+    }
+}
+
+fn publish(
+    client: &mut Client,
+    mailbox: &str,
+    message: Option<String>,
+    lines: bool,
+    options: PublishOptions,
+) -> Result<(), ClientError> {
+    if lines {
+        for line in io::stdin().lock().lines() {
+            client.publish_with(mailbox, line?.as_bytes(), options.clone())?;
+        }
+        return Ok(());
+    }
+
+    let message = match message {
+        Some(message) => message.into_bytes(),
+        None => {
+            let mut message = Vec::new();
+            io::stdin().read_to_end(&mut message)?;
+            message
+        }
+    };
+    let id = client.publish_with(mailbox, &message, options)?;
+    println!("{}", id);
+    Ok(())
+}
+
+fn consume(
+    client: &mut Client,
+    mailbox: &str,
+    count: Option<u64>,
+    no_wait: bool,
+) -> Result<(), ClientError> {
+    // wait in rounds rather than forever, so that a dead connection is
+    // noticed and the client reconnects
+    let wait = if no_wait {
+        Wait::No
+    } else {
+        Wait::For(Duration::from_secs(10))
+    };
+    let stdout = io::stdout();
+    let mut consumed = 0;
+    while count.is_none_or(|count| consumed < count) {
+        let message = match client.retrieve(mailbox, wait)? {
+            Some(message) => message,
+            None if no_wait => break,
+            None => continue,
+        };
+        let mut out = stdout.lock();
+        out.write_all(&message.body)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        client.ack(mailbox, message.id)?;
+        consumed += 1;
+    }
+    Ok(())
+}
+
+fn peek(client: &mut Client, mailbox: &str, count: usize) -> Result<(), ClientError> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for message in client.peek(mailbox, count)? {
+        out.write_all(&message.body)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! Shared access to storage, topics and metrics for connection handlers,
+//! including waiting for messages to be published
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::error;
+
+use crate::metrics::Metrics;
+use crate::protocol::Wait;
+use crate::pubsub::{Closed, Topics};
+use crate::storage::{Batch, Message, PublishOptions, Storage};
+
+/// Longest a waiting consumer sleeps before checking whether its client is
+/// still connected
+const WAIT_ROUND: Duration = Duration::from_secs(1);
+
+/// Storage shared between connection handlers, along with a condition
+/// variable used to wake up consumers waiting for messages, subscribers of
+/// topics and metrics
+pub struct Broker {
+    storage: Mutex<Storage>,
+    published: Condvar,
+    topics: Topics,
+    metrics: Metrics,
+    shutting_down: AtomicBool,
+}
+
+impl Broker {
+    pub fn new(storage: Storage) -> Self {
+        Broker {
+            storage: Mutex::new(storage),
+            published: Condvar::new(),
+            topics: Topics::default(),
+            metrics: Metrics::default(),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// End subscriptions and stop consumers waiting for messages, so that
+    /// connections can be closed
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.topics.close_all(Closed::ShuttingDown);
+        // taking the lock ensures waiting consumers either see the flag or
+        // are already waiting to be notified
+        let _storage = self.lock();
+        self.published.notify_all();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn topics(&self) -> &Topics {
+        &self.topics
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Lock storage, recovering it if another thread panicked while holding
+    /// the lock; storage operations leave it consistent at every step that
+    /// could panic, so it's safe to carry on using it
+    pub fn lock(&self) -> MutexGuard<'_, Storage> {
+        self.storage.lock().unwrap_or_else(|poisoned| {
+            error!("Recovering storage lock poisoned by a panicked thread");
+            poisoned.into_inner()
+        })
+    }
+
+    /// Publish a message and wake up consumers waiting for it; returns ID of
+    /// the message, or None if the mailbox is full
+    pub fn publish(
+        &self,
+        mailbox: String,
+        message: Vec<u8>,
+        options: PublishOptions,
+    ) -> io::Result<Option<u64>> {
+        let id = self.lock().publish(mailbox, message, options)?;
+        self.published.notify_all();
+        Ok(id)
+    }
+
+    /// Publish messages to a mailbox at once and wake up consumers waiting
+    /// for them; returns IDs of the messages, or None if the mailbox doesn't
+    /// have room for all of them
+    pub fn publish_batch(
+        &self,
+        mailbox: String,
+        messages: Vec<(Vec<u8>, PublishOptions)>,
+    ) -> io::Result<Option<Vec<u64>>> {
+        let ids = self.lock().publish_batch(mailbox, messages)?;
+        self.published.notify_all();
+        Ok(ids)
+    }
+
+    /// Reject a delivered message, waking up consumers which may now get it;
+    /// returns false if the message isn't in flight
+    pub fn nack(&self, mailbox: &str, id: u64) -> io::Result<bool> {
+        let found = self.lock().nack(mailbox, id)?;
+        self.published.notify_all();
+        Ok(found)
+    }
+
+    /// Periodic maintenance: discard messages past their TTL, return
+    /// messages with expired leases to their mailboxes, waking up consumers
+    /// waiting for them, then maintain the journal
+    ///
+    /// Compacted journals are written out without holding the storage lock,
+    /// so this must only be called from a single thread.
+    pub fn maintain(&self) -> io::Result<()> {
+        let compaction = {
+            let mut storage = self.lock();
+            storage.discard_expired();
+            let requeued = storage.requeue_expired();
+            // some messages may have been requeued before a failure
+            if !matches!(requeued, Ok(0)) {
+                self.published.notify_all();
+            }
+            requeued?;
+            storage.maintain()?
+        };
+        if let Some(compaction) = compaction {
+            let compacted = compaction.write()?;
+            self.lock().finish_compaction(compacted)?;
+        }
+        Ok(())
+    }
+
+    /// Retrieve a batch of messages, leasing them for `visibility` time and
+    /// waiting for one to be published if the mailbox is empty and `wait`
+    /// allows it; the batch is empty if no message came up
+    ///
+    /// Consumers waiting on the same mailbox are served in the order they
+    /// started waiting, and a consumer which isn't willing to wait doesn't
+    /// get a message ahead of those who are. The batch is taken as a whole
+    /// as soon as there's a message, rather than waiting for it to fill up.
+    ///
+    /// While waiting, `connected` is checked every so often and before
+    /// taking messages, so that a consumer whose client went away stops
+    /// waiting rather than taking messages nobody will receive.
+    pub fn retrieve(
+        &self,
+        mailbox: &str,
+        wait: Wait,
+        visibility: Duration,
+        batch: Batch,
+        connected: impl Fn() -> bool,
+    ) -> Vec<Message> {
+        let mut storage = self.lock();
+        if !storage.has_waiting(mailbox) {
+            let messages = storage.retrieve_batch(mailbox, visibility, batch);
+            if !messages.is_empty() {
+                return messages;
+            }
+        }
+
+        let deadline = match wait {
+            Wait::No => return Vec::new(),
+            Wait::For(timeout) => Some(Instant::now() + timeout),
+            Wait::Forever => None,
+        };
+
+        let ticket = storage.start_waiting(mailbox);
+        let result = loop {
+            if self.is_shutting_down() || !connected() {
+                break Vec::new();
+            }
+            if storage.is_next_waiting(mailbox, ticket) {
+                let messages = storage.retrieve_batch(mailbox, visibility, batch);
+                if !messages.is_empty() {
+                    break messages;
+                }
+            }
+
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                break Vec::new();
+            }
+            // nobody is notified when a delayed message becomes due, so the
+            // consumer first in line wakes up to check for it
+            let due = match storage.is_next_waiting(mailbox, ticket) {
+                true => storage.next_due(mailbox),
+                false => None,
+            };
+            let due =
+                due.map(|due| now + due.duration_since(SystemTime::now()).unwrap_or_default());
+            let wake_up = deadline
+                .into_iter()
+                .chain(due)
+                .fold(now + WAIT_ROUND, Instant::min);
+            storage = self
+                .published
+                .wait_timeout(storage, wake_up.saturating_duration_since(now))
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        };
+
+        storage.stop_waiting(mailbox, ticket);
+        // whoever is next in line may be able to get a message now
+        self.published.notify_all();
+        result
+    }
+}
@@ -0,0 +1,507 @@
+//! Client for the mailbox server, speaking the same protocol types the
+//! server does
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+
+use crate::protocol::{self, Credentials, ProtocolError, Reply, Request, Wait, HEADER_PREFIX};
+use crate::storage::{self, Batch, Message, PublishOptions};
+use crate::stream::Stream;
+use crate::tls::TlsStream;
+
+/// Problems talking to the server
+#[derive(Debug)]
+pub enum ClientError {
+    /// connecting to the server or talking to it failed
+    Io(io::Error),
+    /// server responded with an error status
+    Server { code: u16, message: String },
+    /// server sent a response the client couldn't make sense of
+    Protocol(String),
+    /// request wasn't sent as the server would take it for something else,
+    /// e.g. a mailbox name has a space in it
+    InvalidRequest(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "connection failure: {}", e),
+            ClientError::Server { code, message } => {
+                write!(f, "server error {}: {}", code, message)
+            }
+            ClientError::Protocol(reason) => write!(f, "unexpected response: {}", reason),
+            ClientError::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Io(e) => ClientError::Io(e),
+            e => ClientError::Protocol(e.to_string()),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Timeouts and reconnection behaviour of a client
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// how long connecting to the server may take
+    pub connect_timeout: Duration,
+    /// how long sending a request or waiting for its response may take, on
+    /// top of the time a retrieve is willing to wait for a message
+    pub timeout: Option<Duration>,
+    /// how many times a request is retried on a new connection after the
+    /// connection fails
+    pub retries: u32,
+    /// delay before reconnecting, doubled after every failed attempt
+    pub retry_delay: Duration,
+    /// maximum size of a message received from the server
+    pub max_message_size: usize,
+    /// connect over TLS with this configuration, see `tls::client_config`
+    pub tls: Option<Arc<ClientConfig>>,
+    /// name the server's TLS certificate has to be valid for, by default
+    /// the host the client connects to
+    pub server_name: Option<String>,
+    /// authenticate with these credentials on every connection
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Duration::from_secs(5),
+            timeout: Some(Duration::from_secs(30)),
+            retries: 3,
+            retry_delay: Duration::from_millis(100),
+            max_message_size: 1024 * 1024,
+            tls: None,
+            server_name: None,
+            credentials: None,
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+/// Client connected to a mailbox server, reconnecting when the connection
+/// fails
+///
+/// Requests are retried on a new connection if the connection fails before
+/// the response was received, so a message may be published more than once;
+/// this is in line with the at-least-once delivery the server provides.
+pub struct Client {
+    addr: String,
+    options: ClientOptions,
+    connection: Option<Connection>,
+}
+
+impl Client {
+    /// Connect to the server at `addr` with default options
+    pub fn connect(addr: &str) -> Result<Self> {
+        Client::with_options(addr, ClientOptions::default())
+    }
+
+    /// Connect to the server at `addr`
+    pub fn with_options(addr: &str, options: ClientOptions) -> Result<Self> {
+        let mut client = Client {
+            addr: String::from(addr),
+            options,
+            connection: None,
+        };
+        client.connection = Some(client.open()?);
+        Ok(client)
+    }
+
+    /// Open a new connection to the server, authenticating if configured to
+    fn open(&self) -> Result<Connection> {
+        let mut last_error = None;
+        for addr in self.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.options.connect_timeout) {
+                Ok(stream) => {
+                    // timeouts apply to the TLS handshake as well
+                    stream.set_read_timeout(self.options.timeout)?;
+                    stream.set_write_timeout(self.options.timeout)?;
+                    let stream = match &self.options.tls {
+                        Some(tls) => {
+                            let stream =
+                                TlsStream::connect(stream, Arc::clone(tls), self.server_name()?)?;
+                            Stream::Tls(Arc::new(stream))
+                        }
+                        None => Stream::Plain(stream),
+                    };
+                    let mut connection = Connection {
+                        reader: BufReader::new(stream.try_clone()?),
+                        writer: BufWriter::new(stream.try_clone()?),
+                        stream,
+                    };
+                    // the greeting carries the protocol version
+                    match self.read_reply(&mut connection)? {
+                        Reply::Ok(greeting) if greeting.starts_with("MAILBOX/") => (),
+                        // e.g. the server is shutting down
+                        Reply::Err { code, message } => {
+                            return Err(ClientError::Server { code, message })
+                        }
+                        reply => return Err(unexpected(Some(reply))),
+                    }
+                    if let Some(credentials) = &self.options.credentials {
+                        self.authenticate(&mut connection, credentials)?;
+                    }
+                    return Ok(connection);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to"))
+            .into())
+    }
+
+    fn authenticate(&self, connection: &mut Connection, credentials: &Credentials) -> Result<()> {
+        let request = Request::Auth(credentials.clone());
+        check(&request)?;
+        protocol::write_request(&mut connection.writer, &request)?;
+        connection.writer.flush()?;
+        match self.read_reply(connection)? {
+            Reply::Ok(_) => Ok(()),
+            Reply::Err { code, message } => Err(ClientError::Server { code, message }),
+            reply => Err(unexpected(Some(reply))),
+        }
+    }
+
+    /// Name to verify the server's certificate against
+    fn server_name(&self) -> Result<ServerName<'static>> {
+        let name = match &self.options.server_name {
+            Some(name) => name.as_str(),
+            // the host may be an IPv6 address in brackets
+            None => self
+                .addr
+                .rsplit_once(':')
+                .map_or(self.addr.as_str(), |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        };
+        ServerName::try_from(String::from(name)).map_err(|_| {
+            let message = format!("invalid server name {:?}", name);
+            io::Error::new(io::ErrorKind::InvalidInput, message).into()
+        })
+    }
+
+    fn read_reply(&self, connection: &mut Connection) -> Result<Reply> {
+        match protocol::read_reply(&mut connection.reader, self.options.max_message_size)? {
+            Some(reply) => Ok(reply),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    /// Send a request and read the replies making up its response, retrying
+    /// on a new connection if the connection fails
+    fn request(&mut self, request: &Request, wait: Wait) -> Result<Vec<Reply>> {
+        check(request)?;
+        let mut delay = self.options.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.try_request(request, wait) {
+                // the next attempt reconnects, failing to do so counts as
+                // another failed attempt
+                Err(ClientError::Io(_)) if attempt < self.options.retries => {
+                    attempt += 1;
+                    self.connection = None;
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn try_request(&mut self, request: &Request, wait: Wait) -> Result<Vec<Reply>> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.open()?,
+        };
+
+        // the response only comes once the server is done waiting
+        let timeout = match (wait, self.options.timeout) {
+            (Wait::No, timeout) => timeout,
+            (Wait::For(wait), Some(timeout)) => Some(wait + timeout),
+            _ => None,
+        };
+        connection.stream.set_read_timeout(timeout)?;
+        protocol::write_request(&mut connection.writer, request)?;
+        connection.writer.flush()?;
+
+        let mut replies = Vec::new();
+        loop {
+            let reply = self.read_reply(&mut connection)?;
+            let done = matches!(reply, Reply::Ok(_) | Reply::Err { .. });
+            replies.push(reply);
+            if done {
+                break;
+            }
+        }
+        // only keep the connection once the whole response was read, so
+        // that the next request doesn't get the rest of this response
+        self.connection = Some(connection);
+
+        match replies.last() {
+            Some(Reply::Err { code, message }) => Err(ClientError::Server {
+                code: *code,
+                message: message.clone(),
+            }),
+            _ => Ok(replies),
+        }
+    }
+
+    /// Check the server is alive
+    pub fn ping(&mut self) -> Result<()> {
+        self.request(&Request::Ping, Wait::No).map(|_| ())
+    }
+
+    /// Publish a message to a mailbox, returns ID of the message
+    pub fn publish(&mut self, mailbox: &str, message: &[u8]) -> Result<u64> {
+        self.publish_with(mailbox, message, PublishOptions::default())
+    }
+
+    /// Publish a message to a mailbox with a TTL or headers, returns ID of
+    /// the message
+    pub fn publish_with(
+        &mut self,
+        mailbox: &str,
+        message: &[u8],
+        options: PublishOptions,
+    ) -> Result<u64> {
+        let request = Request::Publish {
+            mailbox: String::from(mailbox),
+            message: message.to_vec(),
+            options,
+        };
+        match self.request(&request, Wait::No)?.pop() {
+            Some(Reply::Ok(id)) => id
+                .parse()
+                .map_err(|_| ClientError::Protocol(format!("invalid message ID {:?}", id))),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Publish messages to a mailbox at once, all of them or none; returns
+    /// IDs of the messages
+    pub fn publish_batch(
+        &mut self,
+        mailbox: &str,
+        messages: Vec<(Vec<u8>, PublishOptions)>,
+    ) -> Result<Vec<u64>> {
+        let request = Request::PublishBatch {
+            mailbox: String::from(mailbox),
+            messages,
+        };
+        let ids = match self.request(&request, Wait::No)?.pop() {
+            Some(Reply::Ok(ids)) => ids,
+            reply => return Err(unexpected(reply)),
+        };
+        let invalid = || ClientError::Protocol(format!("invalid message IDs {:?}", ids));
+        let (first, last) = ids.split_once(' ').ok_or_else(invalid)?;
+        match (first.parse(), last.parse()) {
+            (Ok(first), Ok(last)) => Ok((first..=last).collect()),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Retrieve a message from a mailbox, waiting for one to be published
+    /// if the mailbox is empty and `wait` allows it
+    ///
+    /// The message has to be acknowledged with `ack` once processed,
+    /// otherwise it's delivered again.
+    pub fn retrieve(&mut self, mailbox: &str, wait: Wait) -> Result<Option<Message>> {
+        let messages = self.retrieve_batch(mailbox, wait, Batch::default())?;
+        Ok(messages.into_iter().next())
+    }
+
+    /// Retrieve up to a batch of messages from a mailbox, waiting for one to
+    /// be published if the mailbox is empty and `wait` allows it; each
+    /// message has to be acknowledged as with `retrieve`
+    pub fn retrieve_batch(
+        &mut self,
+        mailbox: &str,
+        wait: Wait,
+        batch: Batch,
+    ) -> Result<Vec<Message>> {
+        let request = Request::Retrieve {
+            mailbox: String::from(mailbox),
+            wait,
+            lease: None,
+            batch,
+        };
+        data_items(self.request(&request, wait)?, parse_message)
+    }
+
+    /// Acknowledge a retrieved message, removing it for good
+    pub fn ack(&mut self, mailbox: &str, id: u64) -> Result<()> {
+        let request = Request::Ack {
+            mailbox: String::from(mailbox),
+            id,
+        };
+        self.request(&request, Wait::No).map(|_| ())
+    }
+
+    /// Reject a retrieved message, returning it to the mailbox
+    pub fn nack(&mut self, mailbox: &str, id: u64) -> Result<()> {
+        let request = Request::Nack {
+            mailbox: String::from(mailbox),
+            id,
+        };
+        self.request(&request, Wait::No).map(|_| ())
+    }
+
+    /// Messages waiting in a mailbox, up to `max` of them, without
+    /// retrieving them
+    pub fn peek(&mut self, mailbox: &str, max: usize) -> Result<Vec<Message>> {
+        let request = Request::Peek {
+            mailbox: String::from(mailbox),
+            max,
+        };
+        data_items(self.request(&request, Wait::No)?, parse_message)
+    }
+
+    /// Number of messages waiting in a mailbox, along with the size of their
+    /// bodies in bytes
+    pub fn count(&mut self, mailbox: &str) -> Result<(usize, usize)> {
+        let request = Request::Count {
+            mailbox: String::from(mailbox),
+        };
+        let info = match self.request(&request, Wait::No)?.pop() {
+            Some(Reply::Ok(info)) => info,
+            reply => return Err(unexpected(reply)),
+        };
+        let invalid = || ClientError::Protocol(format!("invalid count {:?}", info));
+        let (messages, bytes) = info.split_once(' ').ok_or_else(invalid)?;
+        match (messages.parse(), bytes.parse()) {
+            (Ok(messages), Ok(bytes)) => Ok((messages, bytes)),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Remove every message from a mailbox, including those in flight;
+    /// returns the number of messages removed
+    pub fn purge(&mut self, mailbox: &str) -> Result<usize> {
+        let request = Request::Purge {
+            mailbox: String::from(mailbox),
+        };
+        match self.request(&request, Wait::No)?.pop() {
+            Some(Reply::Ok(purged)) => purged
+                .parse()
+                .map_err(|_| ClientError::Protocol(format!("invalid count {:?}", purged))),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Remove a message from a mailbox, whether it's waiting or in flight
+    pub fn delete(&mut self, mailbox: &str, id: u64) -> Result<()> {
+        let request = Request::Delete {
+            mailbox: String::from(mailbox),
+            id,
+        };
+        self.request(&request, Wait::No).map(|_| ())
+    }
+
+    /// Current values of the server's metrics, as pairs of names, including
+    /// labels as in the Prometheus text format, and values
+    pub fn stats(&mut self) -> Result<Vec<(String, f64)>> {
+        data_items(
+            self.request(&Request::Stats, Wait::No)?,
+            |reply| match reply {
+                Reply::Stat { name, value } => Ok((name, value)),
+                reply => Err(unexpected(Some(reply))),
+            },
+        )
+    }
+}
+
+/// Make values out of the data items of a response, i.e. all replies but
+/// the status line ending it
+fn data_items<T>(mut replies: Vec<Reply>, item: impl FnMut(Reply) -> Result<T>) -> Result<Vec<T>> {
+    // drop the status line
+    replies.pop();
+    replies.into_iter().map(item).collect()
+}
+
+/// Make a message out of a `MSG` data item
+fn parse_message(reply: Reply) -> Result<Message> {
+    let (attributes, body) = match reply {
+        Reply::Message { attributes, body } => (attributes, body),
+        reply => return Err(unexpected(Some(reply))),
+    };
+    let optional = |name: &str| -> Result<Option<u64>> {
+        match attributes.iter().find(|(key, _)| key == name) {
+            Some((_, value)) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| ClientError::Protocol(format!("invalid {} {:?}", name, value))),
+            None => Ok(None),
+        }
+    };
+    let attribute = |name: &str| {
+        optional(name)?.ok_or_else(|| ClientError::Protocol(format!("message without {}", name)))
+    };
+    let out_of_range = |name: &str| ClientError::Protocol(format!("{} out of range", name));
+    let headers = attributes
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(HEADER_PREFIX)?;
+            Some((String::from(name), value.clone()))
+        })
+        .collect();
+    Ok(Message {
+        id: attribute("id")?,
+        published: storage::from_millis(attribute("published")?),
+        expires: optional("expires")?.map(storage::from_millis),
+        priority: u8::try_from(optional("priority")?.unwrap_or_default())
+            .map_err(|_| out_of_range("priority"))?,
+        deliver_after: optional("deliver-after")?.map(storage::from_millis),
+        headers,
+        body,
+        deliveries: u32::try_from(attribute("deliveries")?)
+            .map_err(|_| out_of_range("deliveries"))?,
+    })
+}
+
+fn unexpected(reply: Option<Reply>) -> ClientError {
+    ClientError::Protocol(format!("{:?}", reply))
+}
+
+/// Check a request before sending it, so that e.g. a mailbox name with a line
+/// break in it can't smuggle in another request
+fn check(request: &Request) -> Result<()> {
+    protocol::check_request(request).map_err(|e| ClientError::InvalidRequest(e.to_string()))
+}
@@ -0,0 +1,351 @@
+//! Server configuration, taken from command line arguments, environment
+//! variables and a config file, in that order of precedence
+
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+use crate::auth::{Auth, Secret};
+use crate::error::{Error, Result};
+use crate::journal::FsyncPolicy;
+use crate::pubsub::Overflow;
+
+/// Address the server listens on unless configured otherwise
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+
+/// A simple message queue server, speaking a line based protocol over TCP
+/// described in PROTOCOL.md.
+///
+/// Every option can also be set with the environment variable given below,
+/// or in the config file under the same name as the option, e.g.
+/// `idle-timeout = 60`.
+#[derive(Debug, StructOpt)]
+struct Cli {
+    /// Read configuration from this TOML file
+    #[structopt(long, env = "MAILBOX_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Addresses to listen on, e.g. `0.0.0.0:7878` or `[::1]:7878`; can be
+    /// given multiple times or separated with commas [default: 127.0.0.1:7878]
+    #[structopt(long, env = "MAILBOX_LISTEN", use_delimiter = true)]
+    listen: Vec<String>,
+
+    /// Keep messages in a journal at this path so they survive restarts,
+    /// by default messages are only kept in memory
+    #[structopt(long, env = "MAILBOX_JOURNAL", parse(from_os_str))]
+    journal: Option<PathBuf>,
+
+    /// When journal writes are synced to disk: `always`, `never` or at most
+    /// once per given number of milliseconds [default: 1000]
+    #[structopt(long, env = "MAILBOX_FSYNC")]
+    fsync: Option<FsyncPolicy>,
+
+    /// Close connections after this many seconds without a request, 0
+    /// meaning never [default: 300]
+    #[structopt(long, env = "MAILBOX_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+
+    /// Close connections when sending a response takes longer than this many
+    /// seconds, 0 meaning never [default: 0]
+    #[structopt(long, env = "MAILBOX_WRITE_TIMEOUT")]
+    write_timeout: Option<u64>,
+
+    /// Seconds a retrieved message stays leased to the consumer, which has
+    /// to acknowledge it within that time or it's delivered again
+    /// [default: 30]
+    #[structopt(long, env = "MAILBOX_VISIBILITY_TIMEOUT")]
+    visibility_timeout: Option<u64>,
+
+    /// Move messages delivered this many times without being acknowledged to
+    /// the dead-letter mailbox, named after their mailbox with `.dead` added
+    /// [default: 5]
+    #[structopt(long, env = "MAILBOX_MAX_DELIVERIES")]
+    max_deliveries: Option<u32>,
+
+    /// Maximum size of a published message in bytes [default: 1048576]
+    #[structopt(long, env = "MAILBOX_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+
+    /// Maximum number of messages in a mailbox, including those in flight;
+    /// publishing to a full mailbox fails [default: no limit]
+    #[structopt(long, env = "MAILBOX_MAX_QUEUE_DEPTH")]
+    max_queue_depth: Option<usize>,
+
+    /// Maximum number of clients served at once, each by a thread of its
+    /// own; further connections wait to be accepted until a client
+    /// disconnects [default: workers + worker queue]
+    #[structopt(long, env = "MAILBOX_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// Number of worker threads handling requests, i.e. the number of
+    /// requests handled at once [default: 128]
+    #[structopt(long, env = "MAILBOX_WORKERS")]
+    workers: Option<usize>,
+
+    /// Number of requests waiting for a free worker; once it's reached
+    /// clients wait for a worker to free up [default: 128]
+    #[structopt(long, env = "MAILBOX_WORKER_QUEUE")]
+    worker_queue: Option<usize>,
+
+    /// Seconds clients get to finish requests being handled when the server
+    /// shuts down, before they're disconnected [default: 30]
+    #[structopt(long, env = "MAILBOX_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+
+    /// Maximum number of messages buffered for each subscriber of a topic,
+    /// also the default when subscribers don't ask for a smaller buffer
+    /// [default: 100]
+    #[structopt(long, env = "MAILBOX_SUBSCRIBER_BUFFER")]
+    subscriber_buffer: Option<usize>,
+
+    /// What happens when a subscriber's buffer is full by default: `drop`
+    /// the oldest message or `disconnect` the subscriber [default: drop]
+    #[structopt(long, env = "MAILBOX_OVERFLOW")]
+    overflow: Option<Overflow>,
+
+    /// Accept only TLS connections, presenting the certificate chain in this
+    /// PEM file; requires --tls-key
+    #[structopt(long, env = "MAILBOX_TLS_CERT", parse(from_os_str))]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[structopt(long, env = "MAILBOX_TLS_KEY", parse(from_os_str))]
+    tls_key: Option<PathBuf>,
+
+    /// Require clients to present a TLS certificate signed by a CA in this
+    /// PEM file
+    #[structopt(long, env = "MAILBOX_TLS_CLIENT_CA", parse(from_os_str))]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Serve metrics in the Prometheus text format over HTTP at `/metrics`
+    /// on this address, e.g. `127.0.0.1:9090` [default: not served]
+    #[structopt(long, env = "MAILBOX_METRICS_LISTEN")]
+    metrics_listen: Option<String>,
+
+    /// Require requests for metrics over HTTP to carry this token, as in
+    /// `Authorization: Bearer <token>` [default: only serve metrics to
+    /// clients on the loopback interface]
+    #[structopt(long, env = "MAILBOX_METRICS_TOKEN", hide_env_values = true)]
+    metrics_token: Option<String>,
+}
+
+/// Contents of the config file, options are named as on the command line
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    listen: Vec<String>,
+    journal: Option<PathBuf>,
+    fsync: Option<String>,
+    idle_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    visibility_timeout: Option<u64>,
+    max_deliveries: Option<u32>,
+    max_message_size: Option<usize>,
+    max_queue_depth: Option<usize>,
+    max_connections: Option<usize>,
+    workers: Option<usize>,
+    worker_queue: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    subscriber_buffer: Option<usize>,
+    overflow: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_client_ca: Option<PathBuf>,
+    metrics_listen: Option<String>,
+    metrics_token: Option<Secret>,
+    /// credentials and access control, only configurable in the file
+    auth: Auth,
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        let config_error = |reason: String| Error::Config {
+            path: path.to_owned(),
+            reason,
+        };
+        let contents = fs::read_to_string(path).map_err(|e| config_error(e.to_string()))?;
+        toml::from_str(&contents).map_err(|e| config_error(e.to_string()))
+    }
+}
+
+/// Parse an option given as a string in the config file
+fn parse_option<T>(path: &Path, name: &str, value: Option<&str>) -> Result<Option<T>>
+where
+    T: FromStr<Err = String>,
+{
+    value
+        .map(str::parse)
+        .transpose()
+        .map_err(|e| Error::Config {
+            path: path.to_owned(),
+            reason: format!("invalid {}: {}", name, e),
+        })
+}
+
+/// Turn a number of seconds into a timeout, 0 meaning no timeout
+fn timeout(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|t| !t.is_zero())
+}
+
+/// Configuration of the server, defaults apply to options that aren't
+/// configured
+#[derive(Debug)]
+pub struct Config {
+    /// addresses to listen on
+    pub listen: Vec<String>,
+    /// where to keep the journal, if messages should be durable
+    pub journal: Option<PathBuf>,
+    pub fsync: FsyncPolicy,
+    /// close connections after this long without a request
+    pub idle_timeout: Option<Duration>,
+    /// close connections when sending a response takes longer than this
+    pub write_timeout: Option<Duration>,
+    /// default lease of retrieved messages
+    pub visibility_timeout: Duration,
+    pub max_deliveries: u32,
+    pub max_message_size: usize,
+    pub max_queue_depth: Option<usize>,
+    /// number of clients served at once, each taking a thread
+    pub max_connections: usize,
+    /// number of worker threads handling requests
+    pub workers: usize,
+    /// number of requests waiting for a free worker
+    pub worker_queue: usize,
+    /// how long clients get to finish when the server shuts down
+    pub shutdown_timeout: Duration,
+    /// maximum and default size of subscriber buffers
+    pub subscriber_buffer: usize,
+    /// default policy for subscribers whose buffer is full
+    pub overflow: Overflow,
+    /// certificate chain and private key to accept TLS connections with
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// CA to verify client certificates with, if they're required
+    pub tls_client_ca: Option<PathBuf>,
+    /// address to serve metrics on over HTTP, if any
+    pub metrics_listen: Option<String>,
+    /// token HTTP requests for metrics have to carry, if any
+    pub metrics_token: Option<Secret>,
+    /// who may connect and what they may do
+    pub auth: Auth,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec![String::from(DEFAULT_LISTEN)],
+            journal: None,
+            fsync: FsyncPolicy::Periodic(Duration::from_millis(1000)),
+            idle_timeout: timeout(300),
+            write_timeout: None,
+            visibility_timeout: Duration::from_secs(30),
+            max_deliveries: 5,
+            max_message_size: 1024 * 1024,
+            max_queue_depth: None,
+            // clients beyond what the workers and their queue could take at
+            // once would only wait for them anyway
+            max_connections: 256,
+            workers: 128,
+            worker_queue: 128,
+            shutdown_timeout: Duration::from_secs(30),
+            subscriber_buffer: 100,
+            overflow: Overflow::DropOldest,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            metrics_listen: None,
+            metrics_token: None,
+            auth: Auth::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load configuration from command line arguments, falling back to the
+    /// config file if one is given and then to defaults
+    pub fn load() -> Result<Self> {
+        let args = Cli::from_args();
+        let (file, path) = match &args.config {
+            Some(path) => (ConfigFile::read(path)?, path.clone()),
+            None => (ConfigFile::default(), PathBuf::new()),
+        };
+
+        file.auth.validate().map_err(|reason| Error::Config {
+            path: path.clone(),
+            reason,
+        })?;
+        let defaults = Config::default();
+        let listen = if !args.listen.is_empty() {
+            args.listen
+        } else if !file.listen.is_empty() {
+            file.listen.clone()
+        } else {
+            defaults.listen
+        };
+        let fsync = match args.fsync {
+            Some(fsync) => fsync,
+            None => parse_option(&path, "fsync", file.fsync.as_deref())?.unwrap_or(defaults.fsync),
+        };
+        let overflow = match args.overflow {
+            Some(overflow) => overflow,
+            None => parse_option(&path, "overflow", file.overflow.as_deref())?
+                .unwrap_or(defaults.overflow),
+        };
+        let workers = args.workers.or(file.workers).unwrap_or(defaults.workers);
+        let worker_queue = args
+            .worker_queue
+            .or(file.worker_queue)
+            .unwrap_or(defaults.worker_queue);
+
+        Ok(Config {
+            listen,
+            journal: args.journal.or(file.journal),
+            fsync,
+            idle_timeout: match args.idle_timeout.or(file.idle_timeout) {
+                Some(secs) => timeout(secs),
+                None => defaults.idle_timeout,
+            },
+            write_timeout: match args.write_timeout.or(file.write_timeout) {
+                Some(secs) => timeout(secs),
+                None => defaults.write_timeout,
+            },
+            visibility_timeout: args
+                .visibility_timeout
+                .or(file.visibility_timeout)
+                .map_or(defaults.visibility_timeout, Duration::from_secs),
+            max_deliveries: args
+                .max_deliveries
+                .or(file.max_deliveries)
+                .unwrap_or(defaults.max_deliveries),
+            max_message_size: args
+                .max_message_size
+                .or(file.max_message_size)
+                .unwrap_or(defaults.max_message_size),
+            max_queue_depth: args.max_queue_depth.or(file.max_queue_depth),
+            max_connections: args
+                .max_connections
+                .or(file.max_connections)
+                .unwrap_or(workers + worker_queue),
+            workers,
+            worker_queue,
+            shutdown_timeout: args
+                .shutdown_timeout
+                .or(file.shutdown_timeout)
+                .map_or(defaults.shutdown_timeout, Duration::from_secs),
+            subscriber_buffer: args
+                .subscriber_buffer
+                .or(file.subscriber_buffer)
+                .unwrap_or(defaults.subscriber_buffer),
+            overflow,
+            tls_cert: args.tls_cert.or(file.tls_cert),
+            tls_key: args.tls_key.or(file.tls_key),
+            tls_client_ca: args.tls_client_ca.or(file.tls_client_ca),
+            metrics_listen: args.metrics_listen.or(file.metrics_listen),
+            metrics_token: args.metrics_token.map(Secret::from).or(file.metrics_token),
+            auth: file.auth,
+        })
+    }
+}
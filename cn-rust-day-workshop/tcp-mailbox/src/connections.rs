@@ -0,0 +1,135 @@
+//! Tracking of connected clients, used to limit their number and to close
+//! their connections when the server shuts down
+//!
+//! Clients are served a thread each, so accept loops take a `Slot` before
+//! accepting a connection, waiting for one to free up while the server is at
+//! its connection limit; connections waiting to be accepted queue up in the
+//! listener's backlog rather than each taking a thread.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct State {
+    /// connected clients by ID
+    streams: HashMap<u64, TcpStream>,
+    next_id: u64,
+    /// slots taken, by clients connected or about to be accepted
+    slots: usize,
+    /// set once the server shuts down, so no more slots are handed out
+    closing: bool,
+}
+
+/// Clients currently connected to the server
+#[derive(Debug, Default)]
+pub struct Connections {
+    state: Mutex<State>,
+    /// signalled when a client disconnects or a slot is given up
+    disconnected: Condvar,
+}
+
+impl Connections {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Take a slot for a client, waiting for one to free up while `max`
+    /// are taken, in which case `saturated` is called before waiting; None
+    /// once the server is shutting down
+    pub fn reserve(self: &Arc<Self>, max: usize, saturated: impl FnOnce()) -> Option<Slot> {
+        let mut state = self.lock();
+        if !state.closing && state.slots >= max.max(1) {
+            saturated();
+        }
+        while !state.closing && state.slots >= max.max(1) {
+            state = self
+                .disconnected
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        if state.closing {
+            return None;
+        }
+        state.slots += 1;
+        Some(Slot {
+            connections: Arc::clone(self),
+        })
+    }
+
+    /// Register a connected client in the slot taken for it, which stays
+    /// registered and keeps the slot for as long as the returned
+    /// `Connection` is alive
+    pub fn register(&self, slot: Slot, stream: &TcpStream) -> io::Result<Connection> {
+        let stream = stream.try_clone()?;
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.streams.insert(id, stream);
+        Ok(Connection { slot, id })
+    }
+
+    /// Stop reading requests from connected clients, so that they are
+    /// disconnected once the request being handled is done, and wait for
+    /// them to disconnect for up to `timeout`; clients still connected after
+    /// that are disconnected forcefully and their number returned
+    pub fn shut_down(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        // wakes up accept loops waiting for a slot
+        state.closing = true;
+        self.disconnected.notify_all();
+        for stream in state.streams.values() {
+            // fails if the client already disconnected, which is fine
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        while !state.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                for stream in state.streams.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return state.streams.len();
+            }
+            state = self
+                .disconnected
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        0
+    }
+}
+
+/// Room for one more client, counted against the connection limit until
+/// dropped
+#[derive(Debug)]
+pub struct Slot {
+    connections: Arc<Connections>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.connections.lock().slots -= 1;
+        self.connections.disconnected.notify_all();
+    }
+}
+
+/// A connected client, registered in `Connections` until dropped
+#[derive(Debug)]
+pub struct Connection {
+    slot: Slot,
+    id: u64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // the slot is given up right after, which wakes up waiters
+        self.slot.connections.lock().streams.remove(&self.id);
+    }
+}
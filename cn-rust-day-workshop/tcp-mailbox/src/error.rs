@@ -0,0 +1,69 @@
+//! Errors the server can run into
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::protocol::ProtocolError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// configuration was invalid
+    Config { path: PathBuf, reason: String },
+    /// listening socket couldn't be set up, e.g. the port is already in use
+    Bind { addr: String, source: io::Error },
+    /// journal couldn't be opened or replayed
+    Journal { path: PathBuf, source: io::Error },
+    /// TLS was configured incompletely or with invalid certificates or keys
+    Tls(io::Error),
+    /// storage failed to persist a change
+    Storage(io::Error),
+    /// signal handlers couldn't be installed
+    Signals(io::Error),
+    /// reading from or writing to a client connection failed
+    Connection(io::Error),
+    /// client sent a request which couldn't be handled
+    Protocol(ProtocolError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config { path, reason } => {
+                write!(f, "invalid config file {:?}: {}", path, reason)
+            }
+            Error::Bind { addr, source } => {
+                write!(f, "could not listen on {}: {}", addr, source)
+            }
+            Error::Journal { path, source } => {
+                write!(f, "could not open journal {:?}: {}", path, source)
+            }
+            Error::Tls(e) => write!(f, "could not set up TLS: {}", e),
+            Error::Storage(e) => write!(f, "storage failure: {}", e),
+            Error::Signals(e) => write!(f, "could not handle signals: {}", e),
+            Error::Connection(e) => write!(f, "connection failure: {}", e),
+            Error::Protocol(e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } | Error::Journal { source, .. } => Some(source),
+            Error::Tls(e) | Error::Storage(e) | Error::Signals(e) | Error::Connection(e) => Some(e),
+            Error::Config { .. } | Error::Protocol(_) => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Io(e) => Error::Connection(e),
+            e => Error::Protocol(e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
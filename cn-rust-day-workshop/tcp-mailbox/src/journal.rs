@@ -0,0 +1,578 @@
+//! Append-only journal of storage changes, used to make messages durable.
+//!
+//! Every published message is appended as a `P` record, along with its
+//! metadata as attributes in the same form as in `MSG` responses, and every
+//! acknowledged one as a `D` record referring to it by ID. A message going
+//! back to its mailbox gets an `R` record with the number of times it was
+//! delivered. Records written together, e.g. for messages published at once,
+//! are preceded by a `B` record giving their number:
+//!
+//! ```text
+//! P <id> <mailbox> <length> published=<ms> [<attribute>=<value>]...\n<message>\n
+//! D <id> <mailbox>\n
+//! R <id> <mailbox> <deliveries>\n
+//! B <count>\n
+//! N <id>\n
+//! ```
+//!
+//! Replaying the journal on startup rebuilds the mailboxes, skipping messages
+//! which were already acknowledged. A record cut short by a crash can only be
+//! the last one, so replay stops there and the journal is truncated to the
+//! last complete record; a batch cut short is dropped as a whole.
+//!
+//! Compaction rewrites the journal with only the messages not acknowledged
+//! yet, so it doesn't grow forever. It starts with an `N` record giving the
+//! ID of the next message to be published, so that IDs of acknowledged
+//! messages aren't given out again after a restart.
+//!
+//! Any other malformed record means the journal was damaged, in which case
+//! opening it fails instead of dropping the records after it.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::warn;
+
+use crate::protocol::HEADER_PREFIX;
+use crate::storage::{self, Message};
+
+/// When journal writes are flushed to disk with fsync
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsyncPolicy {
+    /// after every write, so that no acknowledged change is ever lost
+    Always,
+    /// at most once per given interval, bounding how much can be lost
+    Periodic(Duration),
+    /// never explicitly, leaving it up to the OS
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// Parse `always`, `never` or an interval in milliseconds
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            ms => ms
+                .parse()
+                .map(|ms| FsyncPolicy::Periodic(Duration::from_millis(ms)))
+                .map_err(|_| {
+                    format!(
+                        "expected always, never or a number of milliseconds, \
+                         got {:?}",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// A single change to the storage
+#[derive(Debug, Eq, PartialEq)]
+pub enum Record {
+    Publish {
+        mailbox: String,
+        message: Message,
+    },
+    Delete {
+        id: u64,
+        mailbox: String,
+    },
+    /// a delivered message went back to its mailbox
+    Requeue {
+        id: u64,
+        mailbox: String,
+        deliveries: u32,
+    },
+    /// records written together, which are only replayed if all of them
+    /// made it to disk
+    Batch(Vec<Record>),
+    /// ID of the next message to be published, at least
+    NextId(u64),
+}
+
+impl Record {
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Record::Publish { mailbox, message } => {
+                write!(
+                    w,
+                    "P {} {} {} published={}",
+                    message.id,
+                    mailbox,
+                    message.body.len(),
+                    storage::to_millis(message.published)
+                )?;
+                if let Some(expires) = message.expires {
+                    write!(w, " expires={}", storage::to_millis(expires))?;
+                }
+                if message.priority != 0 {
+                    write!(w, " priority={}", message.priority)?;
+                }
+                if message.deliveries != 0 {
+                    write!(w, " deliveries={}", message.deliveries)?;
+                }
+                if let Some(after) = message.deliver_after {
+                    write!(w, " deliver-after={}", storage::to_millis(after))?;
+                }
+                for (name, value) in &message.headers {
+                    write!(w, " {}{}={}", HEADER_PREFIX, name, value)?;
+                }
+                writeln!(w)?;
+                w.write_all(&message.body)?;
+                w.write_all(b"\n")
+            }
+            Record::Delete { id, mailbox } => writeln!(w, "D {} {}", id, mailbox),
+            Record::Requeue {
+                id,
+                mailbox,
+                deliveries,
+            } => writeln!(w, "R {} {} {}", id, mailbox, deliveries),
+            Record::Batch(records) => {
+                writeln!(w, "B {}", records.len())?;
+                records.iter().try_for_each(|record| record.write_to(w))
+            }
+            Record::NextId(id) => writeln!(w, "N {}", id),
+        }
+    }
+
+    /// Read the next record, returns None at the end of input
+    fn read_from(r: &mut impl BufRead) -> Result<Option<Self>, ReadError> {
+        let mut header = Vec::new();
+        if r.read_until(b'\n', &mut header)? == 0 {
+            return Ok(None);
+        }
+        if header.pop() != Some(b'\n') {
+            return Err(ReadError::Torn);
+        }
+        let header = String::from_utf8(header)
+            .map_err(|_| ReadError::Corrupt(String::from("record header isn't UTF-8")))?;
+
+        let fields: Vec<_> = header.split_whitespace().collect();
+        let record = match fields.as_slice() {
+            ["P", id, mailbox, len, attributes @ ..] => {
+                let len: u64 = field(len, "length")?;
+                // journals written before messages had metadata have no
+                // attributes
+                let mut message = Message {
+                    id: field(id, "ID")?,
+                    published: SystemTime::now(),
+                    expires: None,
+                    priority: 0,
+                    deliver_after: None,
+                    headers: Vec::new(),
+                    body: Vec::new(),
+                    deliveries: 0,
+                };
+                for attribute in attributes {
+                    let (key, value) = attribute.split_once('=').ok_or_else(|| {
+                        ReadError::Corrupt(format!("invalid attribute {:?}", attribute))
+                    })?;
+                    match key {
+                        "published" => message.published = millis(value, key)?,
+                        "expires" => message.expires = Some(millis(value, key)?),
+                        "priority" => message.priority = field(value, key)?,
+                        "deliveries" => message.deliveries = field(value, key)?,
+                        "deliver-after" => message.deliver_after = Some(millis(value, key)?),
+                        _ => match key.strip_prefix(HEADER_PREFIX) {
+                            Some(name) => message
+                                .headers
+                                .push((String::from(name), String::from(value))),
+                            None => {
+                                return Err(ReadError::Corrupt(format!(
+                                    "unknown attribute {:?}",
+                                    key
+                                )))
+                            }
+                        },
+                    }
+                }
+
+                // the body is read as it comes rather than allocated up
+                // front, so a corrupt length can't exhaust memory
+                let mut body = Vec::new();
+                r.take(len.saturating_add(1)).read_to_end(&mut body)?;
+                if (body.len() as u64) <= len {
+                    return Err(ReadError::Torn);
+                }
+                if body.pop() != Some(b'\n') {
+                    return Err(ReadError::Corrupt(String::from(
+                        "message isn't followed by a newline",
+                    )));
+                }
+                message.body = body;
+                Record::Publish {
+                    mailbox: String::from(*mailbox),
+                    message,
+                }
+            }
+            ["D", id, mailbox] => Record::Delete {
+                id: field(id, "ID")?,
+                mailbox: String::from(*mailbox),
+            },
+            ["R", id, mailbox, deliveries] => Record::Requeue {
+                id: field(id, "ID")?,
+                mailbox: String::from(*mailbox),
+                deliveries: field(deliveries, "delivery count")?,
+            },
+            ["B", count] => {
+                let count: u64 = field(count, "count")?;
+                let mut records = Vec::new();
+                for _ in 0..count {
+                    match Record::read_from(r)? {
+                        Some(Record::Batch(_)) => {
+                            return Err(ReadError::Corrupt(String::from("nested batch")))
+                        }
+                        Some(record) => records.push(record),
+                        None => return Err(ReadError::Torn),
+                    }
+                }
+                Record::Batch(records)
+            }
+            ["N", id] => Record::NextId(field(id, "ID")?),
+            _ => return Err(ReadError::Corrupt(format!("invalid record {:?}", header))),
+        };
+        Ok(Some(record))
+    }
+
+    /// Number of records, counting those in a batch rather than the batch
+    fn count(&self) -> u64 {
+        match self {
+            Record::Batch(records) => records.len() as u64,
+            _ => 1,
+        }
+    }
+}
+
+/// Why a record couldn't be read
+#[derive(Debug)]
+enum ReadError {
+    /// input ends in the middle of the record, as it does when a crash cuts
+    /// the last write short
+    Torn,
+    /// the record is malformed, so the journal was damaged or isn't one
+    Corrupt(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+/// Parse a field of a record, `what` describing it in case it's invalid
+fn field<T: FromStr>(value: &str, what: &str) -> Result<T, ReadError> {
+    value
+        .parse()
+        .map_err(|_| ReadError::Corrupt(format!("invalid {} {:?}", what, value)))
+}
+
+/// Parse a field holding a time in milliseconds since the Unix epoch
+fn millis(value: &str, what: &str) -> Result<SystemTime, ReadError> {
+    field(value, what).map(storage::from_millis)
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// length of the journal, up to the end of the last complete record
+    len: u64,
+    policy: FsyncPolicy,
+    /// when the journal was last synced to disk
+    synced_at: Instant,
+    /// whether there were writes since the last sync
+    dirty: bool,
+    /// number of records appended since the journal was last compacted
+    records: u64,
+}
+
+impl Journal {
+    /// Open the journal at `path`, creating it if it doesn't exist, and
+    /// return it along with all complete records it contains, batches
+    /// being flattened into the records they're made of
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<(Self, Vec<Record>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        let mut reader = BufReader::new(&file);
+        let mut count = 0;
+        loop {
+            match Record::read_from(&mut reader) {
+                Ok(Some(record)) => {
+                    // taken from the reader rather than the record, which
+                    // may be written differently, e.g. with attributes
+                    // older journals don't have
+                    valid_len = reader.stream_position()?;
+                    count += record.count();
+                    match record {
+                        Record::Batch(batch) => records.extend(batch),
+                        record => records.push(record),
+                    }
+                }
+                Ok(None) => break,
+                Err(ReadError::Torn) => {
+                    // the last write before a crash was cut short, drop it
+                    warn!(
+                        path:? = path,
+                        offset = valid_len,
+                        bytes = file.metadata()?.len() - valid_len;
+                        "Journal ends with an incomplete record, truncating"
+                    );
+                    file.set_len(valid_len)?;
+                    file.sync_all()?;
+                    break;
+                }
+                // anything else can't be explained by a crash, so rather than
+                // dropping records which may follow it, refuse to go on
+                Err(ReadError::Corrupt(reason)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt record at offset {}: {}", valid_len, reason),
+                    ))
+                }
+                Err(ReadError::Io(e)) => return Err(e),
+            }
+        }
+
+        let journal = Journal {
+            path: path.to_owned(),
+            file,
+            len: valid_len,
+            policy,
+            synced_at: Instant::now(),
+            dirty: false,
+            records: count,
+        };
+        Ok((journal, records))
+    }
+
+    /// Append a record, syncing it to disk if the policy says so
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut buf = Vec::new();
+        record.write_to(&mut buf)?;
+        if let Err(e) = self.file.write_all(&buf) {
+            // don't leave part of the record behind, records appended after
+            // it would make it look like corruption rather than a torn write
+            let _ = self.file.set_len(self.len);
+            return Err(e);
+        }
+        self.len += buf.len() as u64;
+        self.records += record.count();
+        self.dirty = true;
+
+        match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            _ => self.sync_if_due(),
+        }
+    }
+
+    /// Sync outstanding writes to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty && self.policy != FsyncPolicy::Never {
+            self.file.sync_data()?;
+        }
+        self.dirty = false;
+        self.synced_at = Instant::now();
+        Ok(())
+    }
+
+    /// Sync outstanding writes if the periodic policy interval has elapsed,
+    /// so that writes don't stay unsynced while the journal is idle
+    pub fn sync_if_due(&mut self) -> io::Result<()> {
+        match self.policy {
+            FsyncPolicy::Periodic(interval) if self.synced_at.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of records in the journal
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Start replacing contents of the journal with the given records
+    ///
+    /// The records are only serialized here; writing them out with
+    /// `Compaction::write` doesn't need the journal, so it can be done
+    /// without holding up changes to storage, which are carried over by
+    /// `finish_compaction`. Only one compaction may be in progress at a time.
+    pub fn start_compaction(&self, live: impl Iterator<Item = Record>) -> io::Result<Compaction> {
+        let mut contents = Vec::new();
+        let mut records = 0;
+        for record in live {
+            record.write_to(&mut contents)?;
+            records += record.count();
+        }
+        Ok(Compaction {
+            tmp_path: self.path.with_extension("compact"),
+            contents,
+            records,
+            offset: self.len,
+            journal_records: self.records,
+        })
+    }
+
+    /// Finish a compaction once its records are written out, appending
+    /// records added to the journal meanwhile and replacing the journal
+    ///
+    /// The new journal is written next to the old one and renamed over it
+    /// once complete, so a crash during compaction leaves either the old or
+    /// the new journal in place.
+    pub fn finish_compaction(&mut self, compacted: Compacted) -> io::Result<()> {
+        let Compacted {
+            mut tmp,
+            compaction,
+        } = compacted;
+        let mut old = File::open(&self.path)?;
+        old.seek(SeekFrom::Start(compaction.offset))?;
+        let tail = io::copy(&mut old.take(self.len - compaction.offset), &mut tmp)?;
+        tmp.sync_all()?;
+        fs::rename(&compaction.tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            // make the rename itself durable; not possible on every platform
+            // so errors are ignored
+            let _ = File::open(dir).and_then(|d| d.sync_all());
+        }
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = compaction.contents.len() as u64 + tail;
+        self.records = compaction.records + self.records - compaction.journal_records;
+        self.dirty = false;
+        self.synced_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// Compaction of a journal in progress, see `Journal::start_compaction`
+pub struct Compaction {
+    tmp_path: PathBuf,
+    /// serialized records to write to the new journal
+    contents: Vec<u8>,
+    /// number of records in `contents`
+    records: u64,
+    /// length of the journal when compaction started, anything after it was
+    /// appended meanwhile
+    offset: u64,
+    /// number of records in the journal when compaction started
+    journal_records: u64,
+}
+
+impl Compaction {
+    /// Write the live records to the new journal and sync it to disk
+    pub fn write(self) -> io::Result<Compacted> {
+        let mut tmp = File::create(&self.tmp_path)?;
+        tmp.write_all(&self.contents)?;
+        tmp.sync_all()?;
+        Ok(Compacted {
+            tmp,
+            compaction: self,
+        })
+    }
+}
+
+/// Compaction written out to the new journal, ready to be finished by
+/// `Journal::finish_compaction`
+pub struct Compacted {
+    tmp: File,
+    compaction: Compaction,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// Write `contents` to a journal file named after `name` and open it
+    fn open(name: &str, contents: &[u8]) -> (PathBuf, io::Result<Vec<Record>>) {
+        let path = std::env::temp_dir().join(format!("tcp-mailbox-{}-{}", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        let result = Journal::open(&path, FsyncPolicy::Never).map(|(_, records)| records);
+        (path, result)
+    }
+
+    const PUBLISH: &[u8] = b"P 1 inbox 5 published=1000\nhello\n";
+
+    #[test]
+    fn truncates_torn_tail() {
+        for tail in [
+            &b"P 2 inbox 5 publ"[..],
+            b"P 2 inbox 5 published=1000\nhel",
+            b"B 2\nD 1 inbox\n",
+            b"P 2 inbox 18446744073709551615 published=1000\nhello\n",
+        ] {
+            let (path, records) = open("torn", &[PUBLISH, tail].concat());
+            assert_eq!(records.unwrap().len(), 1);
+            assert_eq!(fs::read(&path).unwrap(), PUBLISH);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuses_corrupt_journal() {
+        for corrupt in [
+            &b"X 2 inbox\n"[..],
+            b"D two inbox\n",
+            b"N one\n",
+            b"P 2 inbox 5 published=1000 colour=red\nhello\n",
+            b"P 2 inbox 3 published=1000\nhello\n",
+            b"B 18446744073709551615\nB 1\n",
+        ] {
+            let contents = [PUBLISH, corrupt, PUBLISH].concat();
+            let (path, records) = open("corrupt", &contents);
+            assert_eq!(records.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(fs::read(&path).unwrap(), contents);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn compaction_keeps_records_appended_meanwhile() {
+        let publish = |id| Record::Publish {
+            mailbox: String::from("inbox"),
+            message: Message {
+                id,
+                published: storage::from_millis(1000),
+                expires: None,
+                priority: 0,
+                deliver_after: None,
+                headers: Vec::new(),
+                body: b"hello".to_vec(),
+                deliveries: 0,
+            },
+        };
+        let (path, _) = open("compaction", b"");
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        journal.append(&publish(1)).unwrap();
+        journal.append(&publish(2)).unwrap();
+        let delete = Record::Delete {
+            id: 1,
+            mailbox: String::from("inbox"),
+        };
+        journal.append(&delete).unwrap();
+
+        let compaction = journal
+            .start_compaction(vec![Record::NextId(3), publish(2)].into_iter())
+            .unwrap();
+        journal.append(&publish(3)).unwrap();
+        let compacted = compaction.write().unwrap();
+        journal.finish_compaction(compacted).unwrap();
+        journal.append(&publish(4)).unwrap();
+        assert_eq!(journal.records(), 4);
+
+        let (_, records) = Journal::open(&path, FsyncPolicy::Never).unwrap();
+        let expected = vec![Record::NextId(3), publish(2), publish(3), publish(4)];
+        assert_eq!(records, expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+//! A simple message queue server speaking a line based protocol over TCP,
+//! along with a client for it
+
+pub mod auth;
+pub mod broker;
+pub mod client;
+pub mod config;
+pub mod connections;
+pub mod error;
+pub mod journal;
+pub mod metrics;
+pub mod pool;
+pub mod protocol;
+pub mod pubsub;
+pub mod server;
+pub mod storage;
+pub mod stream;
+pub mod tls;
@@ -0,0 +1,466 @@
+//! Counters, gauges and histograms describing what the server is doing,
+//! reported to clients with `STATS` and over HTTP in the Prometheus text
+//! format
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::auth::Secret;
+use crate::broker::Broker;
+use crate::pool::ThreadPool;
+
+/// Upper bounds of the buckets of request duration histograms, in seconds
+const DURATION_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0,
+];
+
+/// How long an HTTP client gets to send its request, and to read the response
+const HTTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// HTTP connections served at once, so that a few slow clients don't keep
+/// others from scraping metrics
+const MAX_HTTP_CONNECTIONS: usize = 4;
+
+/// Longest HTTP request accepted, including its headers
+const MAX_HTTP_REQUEST: u64 = 8 * 1024;
+
+/// Counts of requests by how long they took to handle
+#[derive(Debug, Default)]
+struct Histogram {
+    /// requests which took at most the duration of the bucket, and longer
+    /// than that of the previous one
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    /// total duration in seconds
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Metrics kept by connection handlers; metrics about messages are kept by
+/// storage, see `storage::Counts`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_accepted: AtomicU64,
+    connections_deferred: AtomicU64,
+    connections_active: AtomicI64,
+    /// durations of requests by verb
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    /// Count a connection accepted, and active until `disconnected` is
+    /// called
+    pub fn connected(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a time accepting connections was held off, as there were too
+    /// many
+    pub fn deferred(&self) {
+        self.connections_deferred.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long handling a request took
+    pub fn observe(&self, verb: &'static str, duration: Duration) {
+        let mut requests = self.requests.lock().unwrap_or_else(|p| p.into_inner());
+        requests
+            .entry(verb)
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Counter => write!(f, "counter"),
+            Kind::Gauge => write!(f, "gauge"),
+            Kind::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+/// A single value of a metric
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// name of the metric, with a suffix such as `_count` for histograms
+    pub name: String,
+    /// label names and values, e.g. the mailbox the value is about
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    fn new(name: &str, value: f64) -> Self {
+        Sample {
+            name: String::from(name),
+            labels: Vec::new(),
+            value,
+        }
+    }
+
+    fn label(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.labels.push((name, value.into()));
+        self
+    }
+
+    /// Value of a label, if the sample has it
+    pub fn label_value(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl fmt::Display for Sample {
+    /// Name and labels as in the Prometheus text format, e.g.
+    /// `mailbox_messages{mailbox="orders"}`; label values are names of
+    /// mailboxes and verbs, so they never need escaping
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.labels.is_empty() {
+            let labels: Vec<_> = self
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, value))
+                .collect();
+            write!(f, "{{{}}}", labels.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// A metric along with its samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct Family {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    pub samples: Vec<Sample>,
+}
+
+impl Family {
+    fn single(name: &'static str, help: &'static str, kind: Kind, value: f64) -> Self {
+        Family {
+            name,
+            help,
+            kind,
+            samples: vec![Sample::new(name, value)],
+        }
+    }
+}
+
+/// Current values of all metrics
+pub fn collect(broker: &Broker) -> Vec<Family> {
+    let metrics = broker.metrics();
+    let (counts, mailboxes) = {
+        let storage = broker.lock();
+        (storage.counts(), storage.list())
+    };
+    let counter = |name, help, value: u64| Family::single(name, help, Kind::Counter, value as f64);
+
+    let mut families = vec![
+        counter(
+            "connections_accepted_total",
+            "Connections accepted",
+            metrics.connections_accepted.load(Ordering::Relaxed),
+        ),
+        counter(
+            "connections_deferred_total",
+            "Times accepting connections was held off as there were too many",
+            metrics.connections_deferred.load(Ordering::Relaxed),
+        ),
+        Family::single(
+            "connections_active",
+            "Connections currently being served",
+            Kind::Gauge,
+            metrics.connections_active.load(Ordering::Relaxed) as f64,
+        ),
+        counter(
+            "messages_published_total",
+            "Messages published to mailboxes",
+            counts.published,
+        ),
+        counter(
+            "messages_retrieved_total",
+            "Messages delivered to consumers, including redeliveries",
+            counts.retrieved,
+        ),
+        counter(
+            "messages_acknowledged_total",
+            "Messages acknowledged by consumers",
+            counts.acknowledged,
+        ),
+        counter(
+            "messages_expired_total",
+            "Messages discarded as they expired before being delivered",
+            counts.expired,
+        ),
+        counter(
+            "messages_dead_lettered_total",
+            "Messages moved to dead-letter mailboxes",
+            counts.dead_lettered,
+        ),
+        counter(
+            "messages_deleted_total",
+            "Messages deleted or purged by administrators",
+            counts.deleted,
+        ),
+    ];
+
+    let mut depth = Vec::new();
+    let mut in_flight = Vec::new();
+    for (name, waiting, leased) in mailboxes {
+        depth.push(Sample::new("mailbox_messages", waiting as f64).label("mailbox", &*name));
+        in_flight.push(Sample::new("mailbox_in_flight", leased as f64).label("mailbox", name));
+    }
+    families.push(Family {
+        name: "mailbox_messages",
+        help: "Messages waiting in a mailbox",
+        kind: Kind::Gauge,
+        samples: depth,
+    });
+    families.push(Family {
+        name: "mailbox_in_flight",
+        help: "Messages delivered from a mailbox and not acknowledged yet",
+        kind: Kind::Gauge,
+        samples: in_flight,
+    });
+
+    let name = "request_duration_seconds";
+    let mut samples = Vec::new();
+    let requests = metrics.requests.lock().unwrap_or_else(|p| p.into_inner());
+    for (verb, histogram) in requests.iter() {
+        let sample = |suffix: &str, value| {
+            Sample::new(&format!("{}{}", name, suffix), value).label("verb", *verb)
+        };
+        // buckets are cumulative in the Prometheus format
+        let mut cumulative = 0;
+        for (le, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += count;
+            samples.push(sample("_bucket", cumulative as f64).label("le", le.to_string()));
+        }
+        samples.push(sample("_bucket", histogram.count as f64).label("le", "+Inf"));
+        samples.push(sample("_sum", histogram.sum));
+        samples.push(sample("_count", histogram.count as f64));
+    }
+    families.push(Family {
+        name,
+        help: "Time taken to handle requests, including waiting for messages",
+        kind: Kind::Histogram,
+        samples,
+    });
+    families
+}
+
+/// Render metrics in the Prometheus text format
+pub fn render(families: &[Family]) -> String {
+    let mut out = String::new();
+    for family in families {
+        // writing to a string can't fail
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", family.name, family.kind);
+        for sample in &family.samples {
+            let _ = writeln!(out, "{} {}", sample, sample.value);
+        }
+    }
+    out
+}
+
+/// Serve metrics over HTTP at `/metrics` until the server shuts down
+///
+/// Requests are few and quick to serve, so they're served by a small pool of
+/// threads; a client gets `HTTP_TIMEOUT` to send its request, so that slow
+/// or idle clients only hold up a thread for that long.
+///
+/// Metrics name mailboxes, so they're only served to clients with `token`
+/// if given, or else only to clients on the loopback interface.
+pub fn serve(listener: TcpListener, broker: Arc<Broker>, token: Option<Secret>) {
+    // connections aren't queued, but accepted only once a thread is free
+    let pool = ThreadPool::new(MAX_HTTP_CONNECTIONS, 0);
+    for connection_attempt in listener.incoming() {
+        if broker.is_shutting_down() {
+            break;
+        }
+        let stream = match connection_attempt {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!(error:% = e; "Failed to serve metrics");
+                continue;
+            }
+        };
+        let broker = Arc::clone(&broker);
+        let token = token.clone();
+        let job = move || {
+            if let Err(e) = serve_http(stream, &broker, token.as_ref()) {
+                debug!(error:% = e; "Failed to serve metrics");
+            }
+        };
+        if pool.execute(job).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads from a stream until a deadline, after which reading fails as if it
+/// timed out, so that clients can't drag out a request by sending it slowly
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+fn serve_http(stream: TcpStream, broker: &Broker, token: Option<&Secret>) -> io::Result<()> {
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    // clients which aren't allowed anyway don't get to send a request
+    if token.is_none() && !stream.peer_addr()?.ip().is_loopback() {
+        return respond(&stream, "403 Forbidden", "forbidden\n");
+    }
+    let deadline = Deadline {
+        stream: &stream,
+        deadline: Instant::now() + HTTP_TIMEOUT,
+    };
+    let mut reader = BufReader::new(deadline.take(MAX_HTTP_REQUEST));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // only the authorization header matters; a request cut short, e.g. by
+    // being too long, ends up without the empty line ending the headers
+    let mut authorization = None;
+    let mut complete = false;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.ends_with('\n') {
+        let line = header.trim_end();
+        if line.is_empty() {
+            complete = true;
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(String::from(value.trim()));
+            }
+        }
+        header.clear();
+    }
+
+    if !complete {
+        // the rest of a request cut short isn't read, the connection is
+        // closed instead
+        warn!(request:? = request_line; "Malformed HTTP request for metrics");
+        return respond(&stream, "400 Bad Request", "bad request\n");
+    }
+
+    let allowed = token.is_none_or(|token| {
+        authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|guess| token.matches(guess))
+    });
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        _ if !allowed => ("401 Unauthorized", String::from("unauthorized\n")),
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&collect(broker))),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::from("not found\n")),
+        (Some(_), Some(_)) => (
+            "405 Method Not Allowed",
+            String::from("method not allowed\n"),
+        ),
+        _ => {
+            warn!(request:? = request_line; "Malformed HTTP request for metrics");
+            ("400 Bad Request", String::from("bad request\n"))
+        }
+    };
+    respond(&stream, status, &body)
+}
+
+fn respond(mut writer: &TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_requests_by_duration() {
+        let metrics = Metrics::default();
+        metrics.observe("GET", Duration::from_millis(3));
+        metrics.observe("GET", Duration::from_secs(120));
+        let requests = metrics.requests.lock().unwrap();
+        let histogram = &requests["GET"];
+        assert_eq!(histogram.count, 2);
+        assert_eq!(histogram.buckets[3], 1);
+        // the slow request only counts towards the +Inf bucket
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn renders_prometheus_text_format() {
+        let families = [Family {
+            name: "requests",
+            help: "Requests handled",
+            kind: Kind::Counter,
+            samples: vec![
+                Sample::new("requests", 2.0).label("verb", "GET"),
+                Sample::new("requests", 0.5)
+                    .label("verb", "PUB")
+                    .label("le", "+Inf"),
+            ],
+        }];
+        assert_eq!(
+            render(&families),
+            "# HELP requests Requests handled\n\
+             # TYPE requests counter\n\
+             requests{verb=\"GET\"} 2\n\
+             requests{verb=\"PUB\",le=\"+Inf\"} 0.5\n"
+        );
+    }
+}
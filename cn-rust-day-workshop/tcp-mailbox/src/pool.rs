@@ -0,0 +1,139 @@
+//! Fixed-size pool of worker threads, used to bound the number of requests
+//! handled at once however many clients are connected
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::{error, warn};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Job couldn't be run as the pool's workers stopped
+#[derive(Debug)]
+pub struct Stopped;
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "worker pool stopped")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+/// Pool of worker threads running jobs from a bounded queue
+///
+/// Once all workers are busy and the queue is full, submitting another job
+/// blocks until a worker frees up, pushing back on whoever submits jobs.
+pub struct ThreadPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Start `size` workers, with up to `queue` jobs waiting for a free one
+    pub fn new(size: usize, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || work(&receiver))
+            })
+            .collect();
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Run a job on the next free worker, blocking while all workers are
+    /// busy and the queue is full
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> Result<(), Stopped> {
+        let sender = self.sender.as_ref().ok_or(Stopped)?;
+        match sender.try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) => {
+                warn!("All workers busy, waiting for one to free up");
+                sender.send(job).map_err(|_| Stopped)
+            }
+            Err(TrySendError::Disconnected(_)) => Err(Stopped),
+        }
+    }
+}
+
+/// Run jobs until the pool is dropped
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is only held while waiting for a job, not while running it
+        let job = match receiver.lock().unwrap_or_else(|p| p.into_inner()).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // a panicking job shouldn't take a worker with it
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("Worker job panicked");
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Wait for queued jobs to be run and workers to stop
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn runs_jobs_on_bounded_number_of_workers() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(2, 1);
+            for _ in 0..6 {
+                let (running, max_running, done) = (
+                    Arc::clone(&running),
+                    Arc::clone(&max_running),
+                    Arc::clone(&done),
+                );
+                pool.execute(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    done.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 6);
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(1, 1);
+            pool.execute(|| panic!("job failed")).unwrap();
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,1415 @@
+//! Wire protocol spoken between clients and the server, see PROTOCOL.md for
+//! the description of it.
+
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::time::Duration;
+
+use crate::metrics::Sample;
+use crate::pubsub::Overflow;
+use crate::storage::{self, Batch, Message, PublishOptions};
+
+/// Version of the protocol, sent to clients in the greeting
+pub const VERSION: u32 = 1;
+
+/// Maximum length of a request line, not counting payloads
+pub const MAX_LINE_LENGTH: usize = 1024;
+
+/// Maximum length of a mailbox name
+pub const MAX_MAILBOX_LENGTH: usize = 255;
+
+/// Prefix of the attributes carrying message headers
+pub const HEADER_PREFIX: &str = "header.";
+
+/// Maximum length of the headers of a message, as sent in attributes, so
+/// that they leave room for the other attributes of a `MSG` line
+pub const MAX_HEADERS_LENGTH: usize = 512;
+
+/// Maximum length of a header name
+const MAX_HEADER_NAME_LENGTH: usize = 64;
+
+/// Maximum number of messages published with `MPUB` or retrieved with `GET`
+/// at once
+pub const MAX_BATCH_MESSAGES: usize = 1000;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Request {
+    Ping,
+    Auth(Credentials),
+    Publish {
+        mailbox: String,
+        message: Vec<u8>,
+        options: PublishOptions,
+    },
+    /// messages published to a mailbox at once, along with their options
+    PublishBatch {
+        mailbox: String,
+        messages: Vec<(Vec<u8>, PublishOptions)>,
+    },
+    Retrieve {
+        mailbox: String,
+        wait: Wait,
+        /// visibility timeout of the retrieved messages, instead of the
+        /// server default
+        lease: Option<Duration>,
+        /// how many messages to retrieve at once
+        batch: Batch,
+    },
+    Ack {
+        mailbox: String,
+        id: u64,
+    },
+    Nack {
+        mailbox: String,
+        id: u64,
+    },
+    Cast {
+        topic: String,
+        message: Vec<u8>,
+    },
+    Subscribe {
+        topic: String,
+        /// number of messages buffered for the subscriber, instead of the
+        /// server default
+        buffer: Option<usize>,
+        /// what happens when the buffer is full, instead of the server
+        /// default
+        overflow: Option<Overflow>,
+    },
+    Unsubscribe,
+    List,
+    Stats,
+    /// messages waiting in a mailbox, without retrieving them
+    Peek {
+        mailbox: String,
+        max: usize,
+    },
+    /// number and size of messages waiting in a mailbox
+    Count {
+        mailbox: String,
+    },
+    Purge {
+        mailbox: String,
+    },
+    Delete {
+        mailbox: String,
+        id: u64,
+    },
+    Quit,
+}
+
+impl Request {
+    /// Verb of the request, as sent by clients
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Request::Ping => "PING",
+            Request::Auth(_) => "AUTH",
+            Request::Publish { .. } => "PUB",
+            Request::PublishBatch { .. } => "MPUB",
+            Request::Retrieve { .. } => "GET",
+            Request::Ack { .. } => "ACK",
+            Request::Nack { .. } => "NACK",
+            Request::Cast { .. } => "CAST",
+            Request::Subscribe { .. } => "SUB",
+            Request::Unsubscribe => "UNSUB",
+            Request::List => "LIST",
+            Request::Stats => "STATS",
+            Request::Peek { .. } => "PEEK",
+            Request::Count { .. } => "COUNT",
+            Request::Purge { .. } => "PURGE",
+            Request::Delete { .. } => "DEL",
+            Request::Quit => "QUIT",
+        }
+    }
+}
+
+/// What a client authenticates with; neither can contain whitespace
+#[derive(Clone, Eq, PartialEq)]
+pub enum Credentials {
+    /// shared token, identifying the principal it was issued to
+    Token(String),
+    Password {
+        user: String,
+        password: String,
+    },
+}
+
+impl fmt::Debug for Credentials {
+    /// Leave secrets out, e.g. of logs
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?}, .. }}", user),
+        }
+    }
+}
+
+/// How long a retrieve waits for a message to be published if the mailbox is
+/// empty
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Wait {
+    No,
+    For(Duration),
+    Forever,
+}
+
+/// Status codes sent with `ERR` responses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// request was malformed, e.g. unknown verb or invalid arguments
+    BadRequest = 400,
+    /// client has to authenticate first, or its credentials were invalid
+    Unauthorized = 401,
+    /// client isn't allowed to perform the request
+    Forbidden = 403,
+    /// message being acknowledged isn't in flight
+    NotFound = 404,
+    /// request line or payload exceeds the size limit
+    TooLarge = 413,
+    /// subscriber didn't keep up with messages cast to its topic
+    TooSlow = 429,
+    /// request was valid but the server failed to handle it
+    Internal = 500,
+    /// server is too busy to accept the connection
+    Unavailable = 503,
+    /// mailbox reached its maximum depth
+    Full = 507,
+}
+
+/// Problems with a request sent by a client
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// request was malformed, the connection can carry on with the next one
+    Malformed(String),
+    /// request line was too long to be read
+    LineTooLong,
+    /// payload was larger than the maximum message size
+    MessageTooLarge { length: usize, max: usize },
+    /// payload wasn't followed by a line terminator, i.e. its length was
+    /// given incorrectly
+    UnterminatedPayload,
+    /// request with a payload was malformed before the length of its
+    /// payload could be told, e.g. the length isn't a number
+    Unframed(String),
+    /// failed reading the request from the connection
+    Io(io::Error),
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::Malformed(_)
+            | ProtocolError::UnterminatedPayload
+            | ProtocolError::Unframed(_) => ErrorCode::BadRequest,
+            ProtocolError::LineTooLong | ProtocolError::MessageTooLarge { .. } => {
+                ErrorCode::TooLarge
+            }
+            ProtocolError::Io(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// Whether the connection can't carry on after this error since it's not
+    /// known where the next request starts
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, ProtocolError::Malformed(_))
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed(reason) | ProtocolError::Unframed(reason) => {
+                write!(f, "{}", reason)
+            }
+            ProtocolError::LineTooLong => {
+                write!(f, "request line longer than {} bytes", MAX_LINE_LENGTH)
+            }
+            ProtocolError::MessageTooLarge { length, max } => write!(
+                f,
+                "message of {} bytes larger than maximum of {} bytes",
+                length, max
+            ),
+            ProtocolError::UnterminatedPayload => {
+                write!(f, "payload not followed by a line terminator")
+            }
+            ProtocolError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+fn malformed(reason: impl Into<String>) -> ProtocolError {
+    ProtocolError::Malformed(reason.into())
+}
+
+/// Read the next request, returns None once the client disconnects
+pub fn read_request(
+    reader: &mut impl BufRead,
+    max_message_size: usize,
+) -> Result<Option<Request>, ProtocolError> {
+    match read_line(reader)? {
+        Some(line) => parse_request(&line, reader, max_message_size).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Read a line of up to `MAX_LINE_LENGTH` bytes, returns None once the peer
+/// disconnects, even if in the middle of the line
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, ProtocolError> {
+    let mut line = Vec::new();
+    // allow for the line terminator on top of the maximum length
+    let limit = MAX_LINE_LENGTH as u64 + 2;
+    if reader.by_ref().take(limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return if line.len() as u64 == limit {
+            Err(ProtocolError::LineTooLong)
+        } else {
+            Ok(None)
+        };
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| malformed("line is not valid UTF-8"))
+}
+
+/// Parse a request line, reading the payload following it from `reader` if
+/// the request has one
+pub fn parse_request(
+    line: &str,
+    reader: &mut impl BufRead,
+    max_message_size: usize,
+) -> Result<Request, ProtocolError> {
+    let mut args = line.split_whitespace();
+    let verb = args
+        .next()
+        .ok_or_else(|| malformed("empty request"))?
+        .to_ascii_uppercase();
+    let args: Vec<_> = args.collect();
+
+    let request = match (verb.as_str(), args.as_slice()) {
+        ("PING", []) => Request::Ping,
+        ("AUTH", [token]) => Request::Auth(Credentials::Token(String::from(*token))),
+        ("AUTH", [user, password]) => Request::Auth(Credentials::Password {
+            user: String::from(*user),
+            password: String::from(*password),
+        }),
+        ("PUB", [mailbox, length, options @ ..]) => {
+            // read the payload even if the rest is invalid, so that the
+            // connection can carry on with the next request
+            let message = read_payload(reader, parse_length(length)?, max_message_size)?;
+            Request::Publish {
+                mailbox: parse_mailbox(mailbox)?,
+                message,
+                options: parse_publish_options(options)?,
+            }
+        }
+        ("MPUB", [mailbox, count]) => {
+            // messages follow, but not knowing how many there's no telling
+            // where they end
+            let count = match count.parse() {
+                Ok(count) if count > 0 && count <= MAX_BATCH_MESSAGES => count,
+                _ => {
+                    return Err(ProtocolError::Unframed(format!(
+                        "invalid count {:?}, expected 1 to {}",
+                        count, MAX_BATCH_MESSAGES
+                    )))
+                }
+            };
+            read_batch(reader, mailbox, count, max_message_size)?
+        }
+        ("CAST", [topic, length]) => {
+            // as with PUB, consume the payload before rejecting the topic so
+            // that it isn't taken for the next request
+            let message = read_payload(reader, parse_length(length)?, max_message_size)?;
+            Request::Cast {
+                topic: parse_mailbox(topic)?,
+                message,
+            }
+        }
+        ("GET", [mailbox, options @ ..]) => {
+            let mut wait = Wait::No;
+            let mut lease = None;
+            let mut max = None;
+            let mut bytes = None;
+            for option in options {
+                match parse_option(option) {
+                    ("lease", Some(ms)) => match ms.parse() {
+                        Ok(ms) if ms > 0 => lease = Some(Duration::from_millis(ms)),
+                        _ => return Err(invalid_option(option)),
+                    },
+                    ("max", Some(n)) => match n.parse() {
+                        Ok(n) if n > 0 && n <= MAX_BATCH_MESSAGES => max = Some(n),
+                        _ => return Err(invalid_option(option)),
+                    },
+                    ("bytes", Some(n)) => match n.parse() {
+                        Ok(n) if n > 0 => bytes = Some(n),
+                        _ => return Err(invalid_option(option)),
+                    },
+                    ("wait", None) => wait = Wait::Forever,
+                    ("wait", Some(ms)) => {
+                        wait = match ms.parse() {
+                            Ok(0) => Wait::No,
+                            Ok(ms) => Wait::For(Duration::from_millis(ms)),
+                            Err(_) => return Err(invalid_option(option)),
+                        }
+                    }
+                    _ => return Err(invalid_option(option)),
+                }
+            }
+            // a byte budget alone lets as many messages fit as allowed
+            let max = match (max, bytes) {
+                (Some(max), _) => max,
+                (None, Some(_)) => MAX_BATCH_MESSAGES,
+                (None, None) => 1,
+            };
+            Request::Retrieve {
+                mailbox: parse_mailbox(mailbox)?,
+                wait,
+                lease,
+                batch: Batch { max, bytes },
+            }
+        }
+        ("ACK", [mailbox, id]) => Request::Ack {
+            mailbox: parse_mailbox(mailbox)?,
+            id: parse_id(id)?,
+        },
+        ("NACK", [mailbox, id]) => Request::Nack {
+            mailbox: parse_mailbox(mailbox)?,
+            id: parse_id(id)?,
+        },
+        ("SUB", [topic, options @ ..]) => {
+            let mut buffer = None;
+            let mut overflow = None;
+            for option in options {
+                match parse_option(option) {
+                    ("buffer", Some(n)) => match n.parse() {
+                        Ok(n) if n > 0 => buffer = Some(n),
+                        _ => return Err(invalid_option(option)),
+                    },
+                    ("overflow", Some(policy)) => {
+                        overflow = Some(policy.parse().map_err(|_| invalid_option(option))?)
+                    }
+                    _ => return Err(invalid_option(option)),
+                }
+            }
+            Request::Subscribe {
+                topic: parse_mailbox(topic)?,
+                buffer,
+                overflow,
+            }
+        }
+        ("UNSUB", []) => Request::Unsubscribe,
+        ("LIST", []) => Request::List,
+        ("STATS", []) => Request::Stats,
+        ("PEEK", [mailbox, options @ ..]) => {
+            let mut max = 1;
+            for option in options {
+                match parse_option(option) {
+                    ("max", Some(n)) => match n.parse() {
+                        Ok(n) if n > 0 && n <= MAX_BATCH_MESSAGES => max = n,
+                        _ => return Err(invalid_option(option)),
+                    },
+                    _ => return Err(invalid_option(option)),
+                }
+            }
+            Request::Peek {
+                mailbox: parse_mailbox(mailbox)?,
+                max,
+            }
+        }
+        ("COUNT", [mailbox]) => Request::Count {
+            mailbox: parse_mailbox(mailbox)?,
+        },
+        ("PURGE", [mailbox]) => Request::Purge {
+            mailbox: parse_mailbox(mailbox)?,
+        },
+        ("DEL", [mailbox, id]) => Request::Delete {
+            mailbox: parse_mailbox(mailbox)?,
+            id: parse_id(id)?,
+        },
+        ("QUIT", []) => Request::Quit,
+        ("PUB", _) | ("MPUB", _) | ("CAST", _) => {
+            // payloads may follow, but not knowing their length there's no
+            // telling where they end
+            return Err(ProtocolError::Unframed(format!(
+                "wrong number of arguments for {}",
+                verb
+            )));
+        }
+        ("PING", _)
+        | ("AUTH", _)
+        | ("GET", _)
+        | ("ACK", _)
+        | ("NACK", _)
+        | ("SUB", _)
+        | ("UNSUB", _)
+        | ("LIST", _)
+        | ("STATS", _)
+        | ("PEEK", _)
+        | ("COUNT", _)
+        | ("PURGE", _)
+        | ("DEL", _)
+        | ("QUIT", _) => return Err(malformed(format!("wrong number of arguments for {}", verb))),
+        _ => return Err(malformed(format!("unknown verb {:?}", verb))),
+    };
+    Ok(request)
+}
+
+/// Split an option argument in `key=value` or `key` form
+fn parse_option(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (arg, None),
+    }
+}
+
+fn invalid_option(arg: &str) -> ProtocolError {
+    malformed(format!("invalid option {:?}", arg))
+}
+
+/// Parse the options of `PUB`: the TTL, priority, delay and headers of the
+/// message
+fn parse_publish_options(options: &[&str]) -> Result<PublishOptions, ProtocolError> {
+    let mut parsed = PublishOptions::default();
+    for option in options {
+        match parse_option(option) {
+            ("ttl", Some(ms)) => match ms.parse() {
+                Ok(ms) if ms > 0 => parsed.ttl = Some(Duration::from_millis(ms)),
+                _ => return Err(invalid_option(option)),
+            },
+            ("priority", Some(priority)) => {
+                parsed.priority = priority.parse().map_err(|_| invalid_option(option))?
+            }
+            ("delay", Some(ms)) => match ms.parse() {
+                Ok(0) => parsed.delay = None,
+                Ok(ms) => parsed.delay = Some(Duration::from_millis(ms)),
+                Err(_) => return Err(invalid_option(option)),
+            },
+            (key, Some(value)) if key.starts_with(HEADER_PREFIX) => {
+                let name = &key[HEADER_PREFIX.len()..];
+                parsed
+                    .headers
+                    .push((String::from(name), String::from(value)));
+            }
+            _ => return Err(invalid_option(option)),
+        }
+    }
+    check_headers(&parsed.headers)?;
+    Ok(parsed)
+}
+
+/// Check the headers of a message: names are made of ASCII letters, digits
+/// and `-` and given once, values are single words, and they fit in
+/// `MAX_HEADERS_LENGTH` altogether as sent in attributes
+fn check_headers(headers: &[(String, String)]) -> Result<(), ProtocolError> {
+    let mut length = 0;
+    for (i, (name, value)) in headers.iter().enumerate() {
+        let valid_name = !name.is_empty()
+            && name.len() <= MAX_HEADER_NAME_LENGTH
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        let valid_value = !value.is_empty() && !value.chars().any(char::is_whitespace);
+        if !valid_name || !valid_value || headers[..i].iter().any(|(n, _)| n == name) {
+            let header = format!("{}{}={}", HEADER_PREFIX, name, value);
+            return Err(malformed(format!("invalid header {:?}", header)));
+        }
+        length += HEADER_PREFIX.len() + name.len() + 1 + value.len();
+        if length > MAX_HEADERS_LENGTH {
+            return Err(malformed(format!(
+                "headers longer than {} bytes",
+                MAX_HEADERS_LENGTH
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Read the messages of `MPUB` following its request line, each one a line
+/// with its length and options followed by the payload
+///
+/// All payloads are read even if some options are invalid, so that the
+/// connection can carry on with the next request. Payloads are limited to
+/// `max_message_size` altogether.
+fn read_batch(
+    reader: &mut impl BufRead,
+    mailbox: &str,
+    count: usize,
+    max_message_size: usize,
+) -> Result<Request, ProtocolError> {
+    let mut messages = Vec::with_capacity(count);
+    let mut invalid = None;
+    let mut total = 0;
+    for _ in 0..count {
+        // the rest of the batch can't be skipped once a message's length
+        // can't be told, so any error up to it is fatal
+        let line = match read_line(reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Err(ProtocolError::Malformed(reason)) => return Err(ProtocolError::Unframed(reason)),
+            Err(e) => return Err(e),
+        };
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (length, options) = match fields.split_first() {
+            Some((length, options)) => (*length, options),
+            None => {
+                let reason = "missing length of message in batch";
+                return Err(ProtocolError::Unframed(String::from(reason)));
+            }
+        };
+        let length = parse_length(length)?;
+        total += length;
+        if total > max_message_size {
+            return Err(ProtocolError::MessageTooLarge {
+                length: total,
+                max: max_message_size,
+            });
+        }
+        let message = read_payload(reader, length, max_message_size)?;
+        match parse_publish_options(options) {
+            Ok(options) => messages.push((message, options)),
+            Err(e) => invalid = invalid.or(Some(e)),
+        }
+    }
+    if let Some(e) = invalid {
+        return Err(e);
+    }
+    Ok(Request::PublishBatch {
+        mailbox: parse_mailbox(mailbox)?,
+        messages,
+    })
+}
+
+/// Validate a mailbox or topic name, these are made of ASCII letters, digits
+/// and `.`, `_` or `-`
+fn parse_mailbox(name: &str) -> Result<String, ProtocolError> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "._-".contains(c);
+    if name.is_empty() || name.len() > MAX_MAILBOX_LENGTH || !name.chars().all(valid_char) {
+        return Err(malformed(format!("invalid mailbox name {:?}", name)));
+    }
+    Ok(String::from(name))
+}
+
+fn parse_id(id: &str) -> Result<u64, ProtocolError> {
+    id.parse()
+        .map_err(|_| malformed(format!("invalid message ID {:?}", id)))
+}
+
+fn parse_length(length: &str) -> Result<usize, ProtocolError> {
+    length
+        .parse()
+        .map_err(|_| ProtocolError::Unframed(format!("invalid length {:?}", length)))
+}
+
+/// Read a payload of given length followed by a line terminator
+fn read_payload(
+    reader: &mut impl BufRead,
+    length: usize,
+    max_message_size: usize,
+) -> Result<Vec<u8>, ProtocolError> {
+    if length > max_message_size {
+        return Err(ProtocolError::MessageTooLarge {
+            length,
+            max: max_message_size,
+        });
+    }
+
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+
+    let mut terminator = [0; 1];
+    reader.read_exact(&mut terminator)?;
+    if terminator != *b"\n" {
+        // the length didn't match the payload, so there's no telling where
+        // the next request starts
+        return Err(ProtocolError::UnterminatedPayload);
+    }
+    Ok(payload)
+}
+
+/// Send the greeting identifying the server and protocol version
+pub fn write_greeting(w: &mut impl Write) -> io::Result<()> {
+    writeln!(w, "OK MAILBOX/{}", VERSION)
+}
+
+/// Send a successful status line, with optional information following `OK`
+pub fn write_ok(w: &mut impl Write, info: &str) -> io::Result<()> {
+    if info.is_empty() {
+        writeln!(w, "OK")
+    } else {
+        writeln!(w, "OK {}", info)
+    }
+}
+
+/// Send an error status line
+pub fn write_err(w: &mut impl Write, code: ErrorCode, message: &str) -> io::Result<()> {
+    writeln!(w, "ERR {} {}", code as u16, message)
+}
+
+/// Send a message data item, along with the attributes of the message
+pub fn write_message(w: &mut impl Write, message: &Message) -> io::Result<()> {
+    write!(
+        w,
+        "MSG {} id={} deliveries={} published={}",
+        message.body.len(),
+        message.id,
+        message.deliveries,
+        storage::to_millis(message.published)
+    )?;
+    if let Some(expires) = message.expires {
+        write!(w, " expires={}", storage::to_millis(expires))?;
+    }
+    if message.priority != 0 {
+        write!(w, " priority={}", message.priority)?;
+    }
+    if let Some(after) = message.deliver_after {
+        write!(w, " deliver-after={}", storage::to_millis(after))?;
+    }
+    for (name, value) in &message.headers {
+        write!(w, " {}{}={}", HEADER_PREFIX, name, value)?;
+    }
+    writeln!(w)?;
+    w.write_all(&message.body)?;
+    w.write_all(b"\n")
+}
+
+/// Send a message cast to a topic, as part of a response to `SUB`
+pub fn write_cast(w: &mut impl Write, topic: &str, message: &[u8]) -> io::Result<()> {
+    writeln!(w, "MSG {} topic={}", message.len(), topic)?;
+    w.write_all(message)?;
+    w.write_all(b"\n")
+}
+
+/// Send a data item telling a subscriber how many messages it lost because
+/// it didn't keep up
+pub fn write_lost(w: &mut impl Write, count: u64) -> io::Result<()> {
+    writeln!(w, "LOST {}", count)
+}
+
+/// Send a mailbox data item, as part of a response to `LIST`
+pub fn write_mailbox(
+    w: &mut impl Write,
+    name: &str,
+    depth: usize,
+    in_flight: usize,
+) -> io::Result<()> {
+    writeln!(w, "MBOX {} {} {}", name, depth, in_flight)
+}
+
+/// Send a statistic data item, as part of a response to `STATS`
+pub fn write_stat(w: &mut impl Write, sample: &Sample) -> io::Result<()> {
+    writeln!(w, "STAT {} {}", sample, sample.value)
+}
+
+/// Check a request is one the server would parse as given, before a client
+/// sends it: a name or header with spaces or line breaks in it would
+/// otherwise be read as further arguments or requests
+pub fn check_request(request: &Request) -> Result<(), ProtocolError> {
+    match request {
+        Request::Auth(Credentials::Token(token)) => check_word("token", token),
+        Request::Auth(Credentials::Password { user, password }) => {
+            check_word("user name", user)?;
+            check_word("password", password)
+        }
+        Request::Publish {
+            mailbox, options, ..
+        } => {
+            parse_mailbox(mailbox)?;
+            check_headers(&options.headers)
+        }
+        Request::PublishBatch { mailbox, messages } => {
+            parse_mailbox(mailbox)?;
+            messages
+                .iter()
+                .try_for_each(|(_, options)| check_headers(&options.headers))
+        }
+        Request::Retrieve { mailbox, .. }
+        | Request::Ack { mailbox, .. }
+        | Request::Nack { mailbox, .. }
+        | Request::Peek { mailbox, .. }
+        | Request::Count { mailbox }
+        | Request::Purge { mailbox }
+        | Request::Delete { mailbox, .. }
+        | Request::Cast { topic: mailbox, .. }
+        | Request::Subscribe { topic: mailbox, .. } => parse_mailbox(mailbox).map(drop),
+        Request::Ping | Request::Unsubscribe | Request::List | Request::Stats | Request::Quit => {
+            Ok(())
+        }
+    }
+}
+
+/// Check a credential is a single word, as `AUTH` takes them
+fn check_word(what: &str, word: &str) -> Result<(), ProtocolError> {
+    if word.is_empty() || word.chars().any(char::is_whitespace) {
+        return Err(malformed(format!("invalid {}", what)));
+    }
+    Ok(())
+}
+
+/// Send a request, as a client
+pub fn write_request(w: &mut impl Write, request: &Request) -> io::Result<()> {
+    match request {
+        Request::Ping => writeln!(w, "PING"),
+        Request::Auth(Credentials::Token(token)) => writeln!(w, "AUTH {}", token),
+        Request::Auth(Credentials::Password { user, password }) => {
+            writeln!(w, "AUTH {} {}", user, password)
+        }
+        Request::Publish {
+            mailbox,
+            message,
+            options,
+        } => {
+            write!(w, "PUB {} {}", mailbox, message.len())?;
+            write_publish_options(w, options)?;
+            writeln!(w)?;
+            w.write_all(message)?;
+            w.write_all(b"\n")
+        }
+        Request::PublishBatch { mailbox, messages } => {
+            writeln!(w, "MPUB {} {}", mailbox, messages.len())?;
+            for (message, options) in messages {
+                write!(w, "{}", message.len())?;
+                write_publish_options(w, options)?;
+                writeln!(w)?;
+                w.write_all(message)?;
+                w.write_all(b"\n")?;
+            }
+            Ok(())
+        }
+        Request::Retrieve {
+            mailbox,
+            wait,
+            lease,
+            batch,
+        } => {
+            write!(w, "GET {}", mailbox)?;
+            match wait {
+                Wait::No => (),
+                Wait::For(timeout) => write!(w, " wait={}", timeout.as_millis())?,
+                Wait::Forever => write!(w, " wait")?,
+            }
+            if let Some(lease) = lease {
+                write!(w, " lease={}", lease.as_millis())?;
+            }
+            if batch.max != 1 {
+                write!(w, " max={}", batch.max)?;
+            }
+            if let Some(bytes) = batch.bytes {
+                write!(w, " bytes={}", bytes)?;
+            }
+            writeln!(w)
+        }
+        Request::Ack { mailbox, id } => writeln!(w, "ACK {} {}", mailbox, id),
+        Request::Nack { mailbox, id } => writeln!(w, "NACK {} {}", mailbox, id),
+        Request::Cast { topic, message } => {
+            writeln!(w, "CAST {} {}", topic, message.len())?;
+            w.write_all(message)?;
+            w.write_all(b"\n")
+        }
+        Request::Subscribe {
+            topic,
+            buffer,
+            overflow,
+        } => {
+            write!(w, "SUB {}", topic)?;
+            if let Some(buffer) = buffer {
+                write!(w, " buffer={}", buffer)?;
+            }
+            if let Some(overflow) = overflow {
+                write!(w, " overflow={}", overflow)?;
+            }
+            writeln!(w)
+        }
+        Request::Unsubscribe => writeln!(w, "UNSUB"),
+        Request::List => writeln!(w, "LIST"),
+        Request::Stats => writeln!(w, "STATS"),
+        Request::Peek { mailbox, max } => {
+            write!(w, "PEEK {}", mailbox)?;
+            if *max != 1 {
+                write!(w, " max={}", max)?;
+            }
+            writeln!(w)
+        }
+        Request::Count { mailbox } => writeln!(w, "COUNT {}", mailbox),
+        Request::Purge { mailbox } => writeln!(w, "PURGE {}", mailbox),
+        Request::Delete { mailbox, id } => writeln!(w, "DEL {} {}", mailbox, id),
+        Request::Quit => writeln!(w, "QUIT"),
+    }
+}
+
+/// Write the options of a published message, each preceded by a space
+fn write_publish_options(w: &mut impl Write, options: &PublishOptions) -> io::Result<()> {
+    if let Some(ttl) = options.ttl {
+        write!(w, " ttl={}", ttl.as_millis())?;
+    }
+    if options.priority != 0 {
+        write!(w, " priority={}", options.priority)?;
+    }
+    if let Some(delay) = options.delay {
+        write!(w, " delay={}", delay.as_millis())?;
+    }
+    for (name, value) in &options.headers {
+        write!(w, " {}{}={}", HEADER_PREFIX, name, value)?;
+    }
+    Ok(())
+}
+
+/// A single part of a response: a data item or the status line ending it
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// message along with its attributes, e.g. `id`
+    Message {
+        attributes: Vec<(String, String)>,
+        body: Vec<u8>,
+    },
+    Mailbox {
+        name: String,
+        depth: usize,
+        in_flight: usize,
+    },
+    Lost(u64),
+    /// a metric, named as in the Prometheus text format, and its value
+    Stat {
+        name: String,
+        value: f64,
+    },
+    Ok(String),
+    Err {
+        code: u16,
+        message: String,
+    },
+}
+
+/// Read the next part of a response, as a client; returns None once the
+/// server disconnects
+pub fn read_reply(
+    reader: &mut impl BufRead,
+    max_message_size: usize,
+) -> Result<Option<Reply>, ProtocolError> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let invalid = || malformed(format!("invalid reply {:?}", line));
+
+    let reply = match kind {
+        "OK" => Reply::Ok(String::from(rest)),
+        "ERR" => {
+            let (code, message) = rest.split_once(' ').unwrap_or((rest, ""));
+            Reply::Err {
+                code: code.parse().map_err(|_| invalid())?,
+                message: String::from(message),
+            }
+        }
+        "MSG" => {
+            let mut fields = rest.split_whitespace();
+            let length = fields.next().ok_or_else(invalid)?;
+            let attributes = fields
+                .map(|field| match parse_option(field) {
+                    (key, Some(value)) => Ok((String::from(key), String::from(value))),
+                    (_, None) => Err(invalid()),
+                })
+                .collect::<Result<_, _>>()?;
+            Reply::Message {
+                attributes,
+                body: read_payload(reader, parse_length(length)?, max_message_size)?,
+            }
+        }
+        "MBOX" => match rest.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [name, depth, in_flight] => Reply::Mailbox {
+                name: String::from(*name),
+                depth: depth.parse().map_err(|_| invalid())?,
+                in_flight: in_flight.parse().map_err(|_| invalid())?,
+            },
+            _ => return Err(invalid()),
+        },
+        "LOST" => Reply::Lost(rest.parse().map_err(|_| invalid())?),
+        "STAT" => match rest.split_once(' ') {
+            Some((name, value)) => Reply::Stat {
+                name: String::from(name),
+                value: value.parse().map_err(|_| invalid())?,
+            },
+            None => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+    };
+    Ok(Some(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(input: &str) -> Result<Option<Request>, ProtocolError> {
+        read_request(&mut Cursor::new(input), 16)
+    }
+
+    #[test]
+    fn parses_verbs_case_insensitively() {
+        assert_eq!(read("ping\n").unwrap(), Some(Request::Ping));
+        assert_eq!(
+            read("Get orders\n").unwrap(),
+            Some(Request::Retrieve {
+                mailbox: String::from("orders"),
+                wait: Wait::No,
+                lease: None,
+                batch: Batch::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_wait_option() {
+        let wait = |input| match read(input).unwrap() {
+            Some(Request::Retrieve { wait, .. }) => wait,
+            other => panic!("unexpected request {:?}", other),
+        };
+        assert_eq!(wait("GET orders wait\n"), Wait::Forever);
+        assert_eq!(
+            wait("GET orders wait=1500\n"),
+            Wait::For(Duration::from_millis(1500))
+        );
+        assert_eq!(wait("GET orders wait=0\n"), Wait::No);
+        assert!(read("GET orders wait=soon\n").is_err());
+        assert!(read("GET orders linger\n").is_err());
+    }
+
+    #[test]
+    fn parses_lease_option_and_acks() {
+        match read("GET orders lease=5000 wait\n").unwrap() {
+            Some(Request::Retrieve { wait, lease, .. }) => {
+                assert_eq!(wait, Wait::Forever);
+                assert_eq!(lease, Some(Duration::from_millis(5000)));
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+        assert!(read("GET orders lease=0\n").is_err());
+        assert_eq!(
+            read("ack orders 42\n").unwrap(),
+            Some(Request::Ack {
+                mailbox: String::from("orders"),
+                id: 42,
+            })
+        );
+        assert!(read("NACK orders first\n").is_err());
+        assert!(read("NACK orders\n").is_err());
+    }
+
+    #[test]
+    fn parses_subscriptions() {
+        assert_eq!(
+            read("SUB news buffer=10 overflow=disconnect\n").unwrap(),
+            Some(Request::Subscribe {
+                topic: String::from("news"),
+                buffer: Some(10),
+                overflow: Some(Overflow::Disconnect),
+            })
+        );
+        assert!(read("SUB news overflow=block\n").is_err());
+        assert!(read("SUB news buffer=0\n").is_err());
+        assert_eq!(
+            read("CAST news 2\nhi\n").unwrap(),
+            Some(Request::Cast {
+                topic: String::from("news"),
+                message: b"hi".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn writes_requests_as_parsed() {
+        let requests = vec![
+            Request::Ping,
+            Request::Auth(Credentials::Token(String::from("s3cret"))),
+            Request::Auth(Credentials::Password {
+                user: String::from("alice"),
+                password: String::from("pa55"),
+            }),
+            Request::Publish {
+                mailbox: String::from("orders"),
+                message: b"a\nb".to_vec(),
+                options: PublishOptions::default(),
+            },
+            Request::Publish {
+                mailbox: String::from("orders"),
+                message: Vec::new(),
+                options: PublishOptions {
+                    ttl: Some(Duration::from_millis(60000)),
+                    priority: 9,
+                    delay: Some(Duration::from_millis(500)),
+                    headers: vec![(String::from("content-type"), String::from("text/plain"))],
+                },
+            },
+            Request::Retrieve {
+                mailbox: String::from("orders"),
+                wait: Wait::For(Duration::from_millis(250)),
+                lease: Some(Duration::from_millis(1000)),
+                batch: Batch {
+                    max: 10,
+                    bytes: Some(4096),
+                },
+            },
+            Request::PublishBatch {
+                mailbox: String::from("orders"),
+                messages: vec![
+                    (b"a\nb".to_vec(), PublishOptions::default()),
+                    (
+                        Vec::new(),
+                        PublishOptions {
+                            priority: 3,
+                            ..PublishOptions::default()
+                        },
+                    ),
+                ],
+            },
+            Request::Ack {
+                mailbox: String::from("orders"),
+                id: 3,
+            },
+            Request::Subscribe {
+                topic: String::from("news"),
+                buffer: Some(5),
+                overflow: Some(Overflow::DropOldest),
+            },
+            Request::Stats,
+            Request::Peek {
+                mailbox: String::from("orders"),
+                max: 20,
+            },
+            Request::Count {
+                mailbox: String::from("orders"),
+            },
+            Request::Purge {
+                mailbox: String::from("orders"),
+            },
+            Request::Delete {
+                mailbox: String::from("orders"),
+                id: 7,
+            },
+            Request::Quit,
+        ];
+        for request in requests {
+            let mut out = Vec::new();
+            write_request(&mut out, &request).unwrap();
+            let parsed = read_request(&mut Cursor::new(out), 16).unwrap();
+            assert_eq!(parsed, Some(request));
+        }
+    }
+
+    #[test]
+    fn checks_requests_before_sending() {
+        let publish = |mailbox: &str, headers: &[(&str, &str)]| Request::Publish {
+            mailbox: String::from(mailbox),
+            message: Vec::new(),
+            options: PublishOptions {
+                headers: headers
+                    .iter()
+                    .map(|(n, v)| (String::from(*n), String::from(*v)))
+                    .collect(),
+                ..PublishOptions::default()
+            },
+        };
+        assert!(check_request(&publish("orders", &[("trace-id", "a=b")])).is_ok());
+        for request in &[
+            publish("orders\nPURGE orders", &[]),
+            publish("orders ttl=1", &[]),
+            publish("", &[]),
+            publish("orders", &[("trace id", "1")]),
+            publish("orders", &[("trace-id", "1 priority=9")]),
+            publish("orders", &[("trace-id", "1\nPURGE")]),
+            publish("orders", &[("x", "1"), ("x", "2")]),
+            Request::Cast {
+                topic: String::from("news 0\nPURGE orders"),
+                message: Vec::new(),
+            },
+            Request::Count {
+                mailbox: String::from("a b"),
+            },
+            Request::Auth(Credentials::Token(String::from("t\nPURGE orders"))),
+        ] {
+            let err = check_request(request).unwrap_err();
+            assert_eq!(err.code(), ErrorCode::BadRequest, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn reads_replies() {
+        let mut input = Cursor::new("MSG 2 id=7 deliveries=1\nhi\nMBOX a 1 2\nSTAT x{mailbox=\"a\"} 0.5\nERR 404 gone\nOK\n");
+        let mut replies = Vec::new();
+        while let Some(reply) = read_reply(&mut input, 16).unwrap() {
+            replies.push(reply);
+        }
+        assert_eq!(
+            replies,
+            vec![
+                Reply::Message {
+                    attributes: vec![
+                        (String::from("id"), String::from("7")),
+                        (String::from("deliveries"), String::from("1")),
+                    ],
+                    body: b"hi".to_vec(),
+                },
+                Reply::Mailbox {
+                    name: String::from("a"),
+                    depth: 1,
+                    in_flight: 2,
+                },
+                Reply::Stat {
+                    name: String::from("x{mailbox=\"a\"}"),
+                    value: 0.5,
+                },
+                Reply::Err {
+                    code: 404,
+                    message: String::from("gone"),
+                },
+                Reply::Ok(String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_message_attributes() {
+        let mut out = Vec::new();
+        let mut message = Message {
+            id: 7,
+            published: storage::from_millis(1_600_000_000_000),
+            expires: None,
+            priority: 0,
+            deliver_after: None,
+            headers: Vec::new(),
+            body: b"hi".to_vec(),
+            deliveries: 2,
+        };
+        write_message(&mut out, &message).unwrap();
+        assert_eq!(
+            out,
+            b"MSG 2 id=7 deliveries=2 published=1600000000000\nhi\n"
+        );
+
+        out.clear();
+        message.expires = Some(storage::from_millis(1_600_000_060_000));
+        message.priority = 3;
+        message.deliver_after = Some(storage::from_millis(1_600_000_001_000));
+        message.headers = vec![(String::from("correlation-id"), String::from("42"))];
+        write_message(&mut out, &message).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "MSG 2 id=7 deliveries=2 published=1600000000000 expires=1600000060000 priority=3 \
+             deliver-after=1600000001000 header.correlation-id=42\nhi\n"
+        );
+    }
+
+    #[test]
+    fn parses_publish_options() {
+        let options = |input| match read(input).unwrap() {
+            Some(Request::Publish { options, .. }) => options,
+            other => panic!("unexpected request {:?}", other),
+        };
+        assert_eq!(
+            options(
+                "PUB orders 2 ttl=500 priority=7 delay=100 header.content-type=text/plain\nhi\n"
+            ),
+            PublishOptions {
+                ttl: Some(Duration::from_millis(500)),
+                priority: 7,
+                delay: Some(Duration::from_millis(100)),
+                headers: vec![(String::from("content-type"), String::from("text/plain"))],
+            }
+        );
+        // the header value is everything after the first `=`
+        assert_eq!(
+            options("PUB orders 0 header.x=a=b\n\n").headers,
+            vec![(String::from("x"), String::from("a=b"))]
+        );
+
+        let long = format!(
+            "PUB orders 0 header.x={}\n\n",
+            "v".repeat(MAX_HEADERS_LENGTH)
+        );
+        let inputs = [
+            "PUB orders 0 ttl=0\n\n",
+            "PUB orders 0 header.=x\n\n",
+            "PUB orders 0 header.x=\n\n",
+            "PUB orders 0 header.a/b=x\n\n",
+            "PUB orders 0 header.x=1 header.x=2\n\n",
+            "PUB orders 0 priority=high\n\n",
+            "PUB orders 0 priority=256\n\n",
+            "PUB orders 0 delay=-1\n\n",
+            "PUB orders 0 urgent\n\n",
+            &long,
+        ];
+        for input in &inputs {
+            let mut reader = Cursor::new(*input);
+            let err = read_request(&mut reader, 16).unwrap_err();
+            assert!(!err.is_fatal(), "{:?} should not be fatal", input);
+            // the payload was read, so the connection can carry on
+            assert_eq!(reader.position(), input.len() as u64);
+        }
+    }
+
+    #[test]
+    fn reads_length_prefixed_payload() {
+        assert_eq!(
+            read("PUB orders 6\nab\ncd\n\n").unwrap(),
+            Some(Request::Publish {
+                mailbox: String::from("orders"),
+                message: b"ab\ncd\n".to_vec(),
+                options: PublishOptions::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_batches() {
+        let mut input = Cursor::new("MPUB jobs 2\n2 priority=4\nhi\n0\n\nPING\n");
+        assert_eq!(
+            read_request(&mut input, 16).unwrap(),
+            Some(Request::PublishBatch {
+                mailbox: String::from("jobs"),
+                messages: vec![
+                    (
+                        b"hi".to_vec(),
+                        PublishOptions {
+                            priority: 4,
+                            ..PublishOptions::default()
+                        }
+                    ),
+                    (Vec::new(), PublishOptions::default()),
+                ],
+            })
+        );
+        assert_eq!(read_request(&mut input, 16).unwrap(), Some(Request::Ping));
+
+        // the whole batch is read before options are checked
+        let mut input = Cursor::new("MPUB jobs 2\n2 priority=high\nhi\n1\nx\nPING\n");
+        assert!(!read_request(&mut input, 16).unwrap_err().is_fatal());
+        assert_eq!(read_request(&mut input, 16).unwrap(), Some(Request::Ping));
+        assert!(read("MPUB jobs 0\n").is_err());
+        // payloads are limited altogether
+        let err = read("MPUB jobs 2\n10\n0123456789\n10\n0123456789\n").unwrap_err();
+        assert!(err.is_fatal());
+        assert_eq!(err.code(), ErrorCode::TooLarge);
+
+        let batch = |input| match read(input).unwrap() {
+            Some(Request::Retrieve { batch, .. }) => batch,
+            other => panic!("unexpected request {:?}", other),
+        };
+        assert_eq!(
+            batch("GET jobs max=5\n"),
+            Batch {
+                max: 5,
+                bytes: None,
+            }
+        );
+        assert_eq!(batch("GET jobs bytes=100\n").max, MAX_BATCH_MESSAGES);
+        assert!(read("GET jobs max=0\n").is_err());
+        assert!(read("GET jobs max=1001\n").is_err());
+    }
+
+    #[test]
+    fn parses_admin_requests() {
+        assert_eq!(
+            read("peek orders\n").unwrap(),
+            Some(Request::Peek {
+                mailbox: String::from("orders"),
+                max: 1,
+            })
+        );
+        assert!(read("PEEK orders max=0\n").is_err());
+        assert!(read("PEEK orders 5\n").is_err());
+        assert_eq!(
+            read("DEL orders 7\n").unwrap(),
+            Some(Request::Delete {
+                mailbox: String::from("orders"),
+                id: 7,
+            })
+        );
+        assert!(read("DEL orders\n").is_err());
+        assert!(read("PURGE orders now\n").is_err());
+        assert!(read("COUNT\n").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let inputs = [
+            "\n",
+            "FETCH x\n",
+            "GET\n",
+            "GET a b\n",
+            "GET a/b\n",
+            "AUTH\n",
+            "AUTH a b c\n",
+        ];
+        for input in &inputs {
+            let err = read(input).unwrap_err();
+            assert!(!err.is_fatal(), "{:?} should not be fatal", input);
+            assert_eq!(err.code(), ErrorCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn skips_payload_of_invalid_requests() {
+        for input in &[
+            "PUB bad!box 13\nDEL victim 1\n\nPING\n",
+            "CAST bad!topic 13\nDEL victim 1\n\nPING\n",
+        ] {
+            let mut reader = Cursor::new(*input);
+            let err = read_request(&mut reader, 16).unwrap_err();
+            assert!(!err.is_fatal(), "{:?} should not be fatal", input);
+            assert_eq!(err.code(), ErrorCode::BadRequest);
+            assert_eq!(read_request(&mut reader, 16).unwrap(), Some(Request::Ping));
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_messages_and_lines() {
+        let err = read("PUB orders 17\n").unwrap_err();
+        assert!(err.is_fatal());
+        assert_eq!(err.code(), ErrorCode::TooLarge);
+
+        let line = format!("GET {}\n", "x".repeat(MAX_LINE_LENGTH));
+        let err = read(&line).unwrap_err();
+        assert!(err.is_fatal());
+        assert_eq!(err.code(), ErrorCode::TooLarge);
+    }
+
+    #[test]
+    fn rejects_payload_with_wrong_length() {
+        let err = read("PUB orders 2\nabc\n").unwrap_err();
+        assert!(err.is_fatal());
+        assert_eq!(err.code(), ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn rejects_payload_without_valid_length() {
+        for input in &[
+            "PUB orders 5x\nLIST\n",
+            "PUB orders\nLIST\n",
+            "CAST news -1\nLIST\n",
+            "MPUB jobs\n5\nhello\nLIST\n",
+            "MPUB jobs two\n5\nhello\n5\nworld\nLIST\n",
+            "MPUB jobs 0\nLIST\n",
+            // the empty line would be taken for the end of the request,
+            // leaving the request smuggled into the batch to be handled
+            "MPUB jobs 2\n\nLIST\n",
+        ] {
+            let err = read(input).unwrap_err();
+            assert!(err.is_fatal(), "{:?} should be fatal", input);
+            assert_eq!(err.code(), ErrorCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn returns_none_on_disconnect() {
+        assert!(read("").unwrap().is_none());
+        assert!(read("PIN").unwrap().is_none());
+    }
+}
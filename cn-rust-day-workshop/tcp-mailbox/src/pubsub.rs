@@ -0,0 +1,263 @@
+//! Publish/subscribe fan-out: messages cast to a topic are pushed to every
+//! current subscriber of it, rather than queued for a single consumer
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// What happens when a message is cast to a subscriber whose buffer is full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Overflow {
+    /// drop the oldest buffered message to make room, letting the subscriber
+    /// know how many it lost
+    DropOldest,
+    /// end the subscription and disconnect the subscriber
+    Disconnect,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Overflow::DropOldest),
+            "disconnect" => Ok(Overflow::Disconnect),
+            _ => Err(format!("expected drop or disconnect, got {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Overflow::DropOldest => write!(f, "drop"),
+            Overflow::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// Why a subscription ended
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Closed {
+    /// subscriber asked to stop with `UNSUB`
+    Unsubscribed,
+    /// subscriber asked to close the connection with `QUIT`
+    Quit,
+    /// subscriber disconnected or the connection failed
+    Disconnected,
+    /// subscriber didn't keep up and its buffer overflowed
+    TooSlow,
+    /// subscriber sent a request which isn't allowed while subscribed
+    Invalid(String),
+    /// server is shutting down
+    ShuttingDown,
+}
+
+/// Next thing to send to a subscriber
+#[derive(Debug, Eq, PartialEq)]
+pub enum Event {
+    Message(Arc<Vec<u8>>),
+    /// number of messages dropped since the last event because the buffer
+    /// was full
+    Lost(u64),
+    Closed(Closed),
+}
+
+#[derive(Debug)]
+struct Buffer {
+    messages: VecDeque<Arc<Vec<u8>>>,
+    lost: u64,
+    closed: Option<Closed>,
+}
+
+/// A subscriber to a topic, with a bounded buffer of messages cast to the
+/// topic which weren't sent to it yet
+#[derive(Debug)]
+pub struct Subscriber {
+    buffer: Mutex<Buffer>,
+    /// signalled when an event becomes available
+    ready: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Subscriber {
+    fn new(capacity: usize, overflow: Overflow) -> Self {
+        Subscriber {
+            buffer: Mutex::new(Buffer {
+                messages: VecDeque::new(),
+                lost: 0,
+                closed: None,
+            }),
+            ready: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buffer> {
+        // buffers are consistent at every step that could panic
+        self.buffer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Buffer a message cast to the topic, applying the overflow policy if
+    /// the buffer is full
+    fn push(&self, message: Arc<Vec<u8>>) {
+        let mut buffer = self.lock();
+        if buffer.closed.is_some() {
+            return;
+        }
+        if buffer.messages.len() >= self.capacity {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    buffer.messages.pop_front();
+                    buffer.lost += 1;
+                }
+                Overflow::Disconnect => {
+                    buffer.closed = Some(Closed::TooSlow);
+                    buffer.messages.clear();
+                    self.ready.notify_all();
+                    return;
+                }
+            }
+        }
+        buffer.messages.push_back(message);
+        self.ready.notify_all();
+    }
+
+    /// End the subscription, unless it already ended for another reason
+    pub fn close(&self, reason: Closed) {
+        let mut buffer = self.lock();
+        if buffer.closed.is_none() {
+            buffer.closed = Some(reason);
+        }
+        self.ready.notify_all();
+    }
+
+    /// Wait for the next event; once the subscription ended, messages
+    /// still buffered are dropped and only `Event::Closed` is returned
+    pub fn next(&self) -> Event {
+        let mut buffer = self.lock();
+        loop {
+            if let Some(reason) = &buffer.closed {
+                return Event::Closed(reason.clone());
+            }
+            if buffer.lost > 0 {
+                return Event::Lost(std::mem::take(&mut buffer.lost));
+            }
+            if let Some(message) = buffer.messages.pop_front() {
+                return Event::Message(message);
+            }
+            buffer = self
+                .ready
+                .wait(buffer)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+/// Current subscribers of every topic
+#[derive(Debug, Default)]
+pub struct Topics {
+    subscribers: Mutex<HashMap<String, Vec<Arc<Subscriber>>>>,
+}
+
+impl Topics {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<Arc<Subscriber>>>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Subscribe to a topic with a buffer of `capacity` messages
+    pub fn subscribe(&self, topic: &str, capacity: usize, overflow: Overflow) -> Arc<Subscriber> {
+        let subscriber = Arc::new(Subscriber::new(capacity, overflow));
+        self.lock()
+            .entry(String::from(topic))
+            .or_default()
+            .push(Arc::clone(&subscriber));
+        subscriber
+    }
+
+    /// Remove a subscriber from a topic
+    pub fn unsubscribe(&self, topic: &str, subscriber: &Arc<Subscriber>) {
+        let mut topics = self.lock();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.retain(|s| !Arc::ptr_eq(s, subscriber));
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+
+    /// End all subscriptions
+    pub fn close_all(&self, reason: Closed) {
+        for subscriber in self.lock().values().flatten() {
+            subscriber.close(reason.clone());
+        }
+    }
+
+    /// Push a message to all current subscribers of a topic, returns the
+    /// number of subscribers it was pushed to
+    pub fn cast(&self, topic: &str, message: Vec<u8>) -> usize {
+        let topics = self.lock();
+        let subscribers = match topics.get(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let message = Arc::new(message);
+        for subscriber in subscribers {
+            subscriber.push(Arc::clone(&message));
+        }
+        subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(body: &str) -> Event {
+        Event::Message(Arc::new(body.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn casts_to_every_subscriber() {
+        let topics = Topics::default();
+        let a = topics.subscribe("news", 10, Overflow::DropOldest);
+        let b = topics.subscribe("news", 10, Overflow::DropOldest);
+        assert_eq!(topics.cast("news", b"hello".to_vec()), 2);
+        assert_eq!(topics.cast("sports", b"goal".to_vec()), 0);
+        assert_eq!(a.next(), message("hello"));
+        assert_eq!(b.next(), message("hello"));
+
+        topics.unsubscribe("news", &a);
+        assert_eq!(topics.cast("news", b"bye".to_vec()), 1);
+    }
+
+    #[test]
+    fn drops_oldest_messages_when_full() {
+        let topics = Topics::default();
+        let sub = topics.subscribe("news", 2, Overflow::DropOldest);
+        for body in &["1", "2", "3", "4"] {
+            topics.cast("news", body.as_bytes().to_vec());
+        }
+        assert_eq!(sub.next(), Event::Lost(2));
+        assert_eq!(sub.next(), message("3"));
+        assert_eq!(sub.next(), message("4"));
+    }
+
+    #[test]
+    fn disconnects_slow_subscribers() {
+        let topics = Topics::default();
+        let sub = topics.subscribe("news", 1, Overflow::Disconnect);
+        topics.cast("news", b"1".to_vec());
+        topics.cast("news", b"2".to_vec());
+        assert_eq!(sub.next(), Event::Closed(Closed::TooSlow));
+        sub.close(Closed::Unsubscribed);
+        assert_eq!(sub.next(), Event::Closed(Closed::TooSlow));
+    }
+}
@@ -0,0 +1,771 @@
+//! The mailbox server: accepts connections on its listen addresses and
+//! serves their requests until shut down
+
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use log::{error, info, warn};
+use rustls::ServerConfig;
+
+use crate::auth::{Auth, Permission};
+use crate::broker::Broker;
+use crate::config::Config;
+use crate::connections::Connections;
+use crate::error::{Error, Result};
+use crate::journal::Journal;
+use crate::metrics;
+use crate::pool::ThreadPool;
+use crate::protocol::{self, Credentials, ErrorCode, ProtocolError, Request, Wait};
+use crate::pubsub::{Closed, Event, Subscriber};
+use crate::storage::{Limits, Storage, MAINTENANCE_INTERVAL};
+use crate::stream::Stream;
+use crate::tls::{self, TlsStream};
+
+/// Failed attempts to authenticate after which a client is disconnected, to
+/// slow down guessing credentials
+const MAX_AUTH_FAILURES: u32 = 3;
+
+/// A running server, serving clients until shut down
+///
+/// Listening on port 0 lets the OS pick a free port, which `local_addr`
+/// reports, e.g. to run a server per test.
+pub struct Server {
+    broker: Arc<Broker>,
+    config: Arc<Config>,
+    connections: Arc<Connections>,
+    /// addresses actually listened on
+    addrs: Vec<SocketAddr>,
+    accept_threads: Vec<JoinHandle<()>>,
+    /// address metrics are served on over HTTP, and the thread serving them
+    metrics: Option<(SocketAddr, JoinHandle<()>)>,
+}
+
+impl Server {
+    /// Open storage, listen on all configured addresses and start serving
+    /// clients in the background
+    pub fn start(config: Config) -> Result<Self> {
+        let limits = Limits {
+            max_deliveries: config.max_deliveries,
+            max_queue_depth: config.max_queue_depth,
+        };
+        let storage = match &config.journal {
+            Some(path) => {
+                let (journal, records) =
+                    Journal::open(path, config.fsync).map_err(|source| Error::Journal {
+                        path: path.clone(),
+                        source,
+                    })?;
+                Storage::with_journal(journal, records, limits)
+            }
+            None => Storage::new(limits),
+        };
+        let broker = Arc::new(Broker::new(storage));
+
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                tls::server_config(cert, key, config.tls_client_ca.as_deref())
+                    .map_err(Error::Tls)?,
+            ),
+            (None, None) if config.tls_client_ca.is_none() => None,
+            _ => {
+                let reason = "TLS requires both a certificate and its private key";
+                return Err(Error::Tls(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    reason,
+                )));
+            }
+        };
+
+        // bind all addresses upfront, so that failing to bind any of them
+        // stops the server straight away
+        let listeners = config
+            .listen
+            .iter()
+            .map(|addr| {
+                TcpListener::bind(addr.as_str()).map_err(|source| Error::Bind {
+                    addr: addr.clone(),
+                    source,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|source| Error::Bind {
+                addr: config.listen.join(","),
+                source,
+            })?;
+        let metrics_listener = match &config.metrics_listen {
+            Some(addr) => {
+                let bind_error = |source| Error::Bind {
+                    addr: addr.clone(),
+                    source,
+                };
+                let listener = TcpListener::bind(addr.as_str()).map_err(bind_error)?;
+                let local_addr = listener.local_addr().map_err(bind_error)?;
+                Some((listener, local_addr))
+            }
+            None => None,
+        };
+        for addr in &addrs {
+            info!(addr:% = addr, tls = tls.is_some(); "Listening");
+        }
+
+        {
+            let broker = Arc::clone(&broker);
+            thread::spawn(move || loop {
+                thread::sleep(MAINTENANCE_INTERVAL);
+                if broker.is_shutting_down() {
+                    break;
+                }
+                if let Err(e) = broker.maintain() {
+                    error!(error:% = e; "Storage maintenance failed");
+                }
+            });
+        }
+
+        let pool = Arc::new(ThreadPool::new(config.workers, config.worker_queue));
+        let config = Arc::new(config);
+        let connections = Arc::new(Connections::default());
+        let accept_threads = listeners
+            .into_iter()
+            .map(|listener| {
+                let broker = Arc::clone(&broker);
+                let pool = Arc::clone(&pool);
+                let config = Arc::clone(&config);
+                let connections = Arc::clone(&connections);
+                let tls = tls.clone();
+                thread::spawn(move || accept(listener, broker, pool, config, &connections, tls))
+            })
+            .collect();
+        let metrics = metrics_listener.map(|(listener, addr)| {
+            info!(addr:% = addr; "Serving metrics over HTTP");
+            let broker = Arc::clone(&broker);
+            let token = config.metrics_token.clone();
+            (
+                addr,
+                thread::spawn(move || metrics::serve(listener, broker, token)),
+            )
+        });
+
+        Ok(Server {
+            broker,
+            config,
+            connections,
+            addrs,
+            accept_threads,
+            metrics,
+        })
+    }
+
+    /// Address the server listens on, the first one if it listens on several
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// Addresses the server listens on, in the order they were configured
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Address metrics are served on over HTTP, if configured to
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics.as_ref().map(|(addr, _)| *addr)
+    }
+
+    /// Stop accepting connections, let connected clients finish the requests
+    /// being handled for up to the shutdown timeout, then sync storage
+    pub fn shut_down(self) -> Result<()> {
+        // stop accepting connections: accept loops notice once woken up by a
+        // connection, as there's no other way to interrupt them, so the
+        // broker has to be shutting down before they're woken up
+        self.broker.shut_down();
+        let forced = {
+            let connections = Arc::clone(&self.connections);
+            let timeout = self.config.shutdown_timeout;
+            thread::spawn(move || connections.shut_down(timeout))
+        };
+        for addr in &self.addrs {
+            let _ = TcpStream::connect(addr);
+        }
+        if let Some((addr, _)) = &self.metrics {
+            let _ = TcpStream::connect(addr);
+        }
+        for thread in self.accept_threads {
+            let _ = thread.join();
+        }
+        if let Some((_, thread)) = self.metrics {
+            let _ = thread.join();
+        }
+
+        match forced.join() {
+            Ok(0) | Err(_) => (),
+            Ok(forced) => {
+                warn!(clients = forced; "Disconnected clients which didn't finish in time")
+            }
+        }
+        let result = self.broker.lock().shut_down();
+        result.map_err(Error::Storage)
+    }
+}
+
+/// Accept connections on a listener until the server shuts down, reading
+/// requests from each client on a thread of its own and handling them on
+/// the worker pool
+///
+/// At most `max_connections` clients are served at once, across all
+/// listeners; while that many are connected, no more connections are
+/// accepted until one disconnects, so a burst of clients waits in the
+/// listener's backlog instead of each taking a thread.
+fn accept(
+    listener: TcpListener,
+    broker: Arc<Broker>,
+    pool: Arc<ThreadPool>,
+    config: Arc<Config>,
+    connections: &Arc<Connections>,
+    tls: Option<Arc<ServerConfig>>,
+) {
+    let mut incoming = listener.incoming();
+    loop {
+        let max = config.max_connections;
+        let slot = connections.reserve(max, || {
+            broker.metrics().deferred();
+            warn!(max; "Too many connections, holding off accepting more");
+        });
+        // no slot once the server is shutting down
+        let slot = match slot {
+            Some(slot) => slot,
+            None => break,
+        };
+        let connection_attempt = match incoming.next() {
+            Some(connection_attempt) => connection_attempt,
+            None => break,
+        };
+        // checked against the broker, which starts shutting down before
+        // accept loops are woken up
+        if broker.is_shutting_down() {
+            break;
+        }
+        let stream = match connection_attempt {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error:% = e; "Error connecting");
+                continue;
+            }
+        };
+
+        let connection = match connections.register(slot, &stream) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(error:% = e; "Error connecting");
+                continue;
+            }
+        };
+
+        // Multiple threads need to be able to write to storage, but
+        // collections are not thread-safe so `Broker` wraps our
+        // mailboxes in a mutex, locked by the thread doing the
+        // modification.
+        // We also need to ensure collection isn't deallocated before
+        // all threads release it, so we wrap it in a reference counter
+        // (`Arc`).
+        let thread_handle = Arc::clone(&broker);
+        let config = Arc::clone(&config);
+        let pool = Arc::clone(&pool);
+        let tls = tls.clone();
+        thread_handle.metrics().connected();
+        let spawned = thread::Builder::new().spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle_client(stream, tls, &thread_handle, &config, &pool) {
+                warn!(peer:?, error:% = e; "Client error");
+            }
+            thread_handle.metrics().disconnected();
+            drop(connection);
+        });
+        if let Err(e) = spawned {
+            error!(error:% = e; "Could not start serving client");
+            broker.metrics().disconnected();
+        }
+    }
+}
+
+/// Serve requests from a client until it disconnects, quits or stays idle
+/// for longer than the idle timeout, after completing the TLS handshake if
+/// the server uses TLS
+///
+/// Requests are handled by a worker, except for those which may wait for as
+/// long as the client likes, i.e. subscriptions and `GET ... wait`, so that
+/// waiting clients don't hold up others.
+fn handle_client(
+    stream: TcpStream,
+    tls: Option<Arc<ServerConfig>>,
+    broker: &Arc<Broker>,
+    config: &Arc<Config>,
+    pool: &ThreadPool,
+) -> Result<()> {
+    let peer = stream.peer_addr().map_err(Error::Connection)?;
+    info!(peer:% = peer; "Client connected");
+    // timeouts apply to the handshake as well
+    stream
+        .set_read_timeout(config.idle_timeout)
+        .and_then(|_| stream.set_write_timeout(config.write_timeout))
+        .map_err(Error::Connection)?;
+    let stream = Arc::new(match tls {
+        Some(tls) => {
+            let stream = TlsStream::accept(stream, tls).map_err(Error::Connection)?;
+            Stream::Tls(Arc::new(stream))
+        }
+        None => Stream::Plain(stream),
+    });
+    let mut reader = BufReader::new(&*stream);
+    let mut writer = BufWriter::new(&*stream);
+    if broker.is_shutting_down() {
+        // the connection was queued up before the server started shutting
+        // down
+        let message = "server is shutting down";
+        protocol::write_err(&mut writer, ErrorCode::Unavailable, message)
+            .and_then(|_| writer.flush())
+            .map_err(Error::Connection)?;
+        return Ok(());
+    }
+    protocol::write_greeting(&mut writer).map_err(Error::Connection)?;
+    writer.flush().map_err(Error::Connection)?;
+
+    // who the client authenticated as
+    let mut principal = None;
+    let mut auth_failures = 0;
+    loop {
+        let request = match protocol::read_request(&mut reader, config.max_message_size) {
+            Ok(Some(request)) => request,
+            // client disconnected
+            Ok(None) => break,
+            Err(ProtocolError::Io(e)) if is_timeout(&e) => {
+                info!(peer:% = peer; "Client idle, disconnecting");
+                break;
+            }
+            // reading stopped as the server is shutting down, which TLS
+            // takes for the client disconnecting without ending the session
+            Err(ProtocolError::Io(_)) if broker.is_shutting_down() => break,
+            Err(e) => {
+                // let the client know what was wrong with the request, unless
+                // the connection itself failed
+                if !matches!(e, ProtocolError::Io(_)) {
+                    protocol::write_err(&mut writer, e.code(), &e.to_string())
+                        .and_then(|_| writer.flush())
+                        .map_err(Error::Connection)?;
+                }
+                if e.is_fatal() {
+                    return Err(e.into());
+                }
+                info!(peer:% = peer, error:% = e; "Client sent malformed request");
+                continue;
+            }
+        };
+
+        let carry_on = match config.auth.check(principal.as_deref(), &request) {
+            Err(denied) => {
+                protocol::write_err(&mut writer, denied.code(), &denied.to_string()).map(|_| true)
+            }
+            Ok(()) => match request {
+                Request::Auth(credentials) => authenticate(
+                    &config.auth,
+                    &credentials,
+                    peer,
+                    (&mut principal, &mut auth_failures),
+                    &mut writer,
+                ),
+                Request::Subscribe {
+                    topic,
+                    buffer,
+                    overflow,
+                } => {
+                    let buffer = buffer.map_or(config.subscriber_buffer, |b| {
+                        b.min(config.subscriber_buffer)
+                    });
+                    let overflow = overflow.unwrap_or(config.overflow);
+                    let subscriber = broker.topics().subscribe(&topic, buffer, overflow);
+                    let closed = serve_subscription(
+                        &stream,
+                        &mut reader,
+                        &mut writer,
+                        config,
+                        &topic,
+                        &subscriber,
+                    );
+                    broker.topics().unsubscribe(&topic, &subscriber);
+                    end_subscription(&mut writer, closed.map_err(Error::Connection)?)
+                }
+                request => {
+                    // subscriptions last as long as clients like, so only
+                    // other requests are timed
+                    let started = Instant::now();
+                    let verb = request.verb();
+                    let principal = principal.as_deref();
+                    let result = if may_wait(&request) {
+                        handle_request(request, broker, config, principal, &stream, &mut writer)
+                    } else {
+                        handle_on_worker(
+                            pool,
+                            request,
+                            principal,
+                            broker,
+                            config,
+                            &stream,
+                            &mut writer,
+                        )
+                    };
+                    broker.metrics().observe(verb, started.elapsed());
+                    result
+                }
+            },
+        };
+        if !carry_on.map_err(Error::Connection)? {
+            break;
+        }
+
+        // responses to pipelined requests are sent together once all of
+        // them were handled
+        if reader.buffer().is_empty() {
+            writer.flush().map_err(Error::Connection)?;
+        }
+    }
+    writer.flush().map_err(Error::Connection)
+}
+
+/// Whether handling a request may wait for as long as the client likes
+fn may_wait(request: &Request) -> bool {
+    match request {
+        Request::Retrieve { wait, .. } => *wait != Wait::No,
+        Request::Subscribe { .. } => true,
+        _ => false,
+    }
+}
+
+/// Handle a request on a worker of the pool, then write its response to
+/// `writer`; returns whether the connection should carry on
+///
+/// The worker writes the response to a buffer, so that it isn't held up by
+/// clients slow to read it.
+fn handle_on_worker(
+    pool: &ThreadPool,
+    request: Request,
+    principal: Option<&str>,
+    broker: &Arc<Broker>,
+    config: &Arc<Config>,
+    stream: &Arc<Stream>,
+    writer: &mut impl Write,
+) -> io::Result<bool> {
+    let (handled, response) = mpsc::sync_channel(1);
+    let principal = principal.map(String::from);
+    let (broker, config, stream) = (Arc::clone(broker), Arc::clone(config), Arc::clone(stream));
+    let job = move || {
+        let mut response = Vec::new();
+        let carry_on = handle_request(
+            request,
+            &broker,
+            &config,
+            principal.as_deref(),
+            &stream,
+            &mut response,
+        );
+        let _ = handled.send((response, carry_on));
+    };
+    if pool.execute(job).is_err() {
+        protocol::write_err(writer, ErrorCode::Unavailable, "server is shutting down")?;
+        return Ok(false);
+    }
+    match response.recv() {
+        Ok((response, carry_on)) => {
+            writer.write_all(&response)?;
+            carry_on
+        }
+        // the job panicked before responding, leaving the connection in an
+        // unknown state
+        Err(_) => Err(io::Error::other("request could not be handled")),
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Whether the client is still connected, checked without consuming any
+/// requests it may have sent meanwhile
+fn is_connected(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        // can't tell without blocking, so assume it is
+        return true;
+    }
+    let result = stream.peek(&mut [0; 1]);
+    let _ = stream.set_nonblocking(false);
+    match result {
+        Ok(read) => read > 0,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    }
+}
+
+/// Push messages cast to a topic to a subscribed client until the
+/// subscription ends, returning why it ended
+///
+/// Requests are read on a separate thread meanwhile, so that the client can
+/// unsubscribe at any point.
+fn serve_subscription(
+    stream: &Stream,
+    reader: &mut BufReader<&Stream>,
+    writer: &mut BufWriter<&Stream>,
+    config: &Config,
+    topic: &str,
+    subscriber: &Subscriber,
+) -> io::Result<Closed> {
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let reason = loop {
+                match protocol::read_request(reader, config.max_message_size) {
+                    Ok(Some(Request::Unsubscribe)) => break Closed::Unsubscribed,
+                    Ok(Some(Request::Quit)) => break Closed::Quit,
+                    Ok(Some(_)) => {
+                        let reason = "only UNSUB and QUIT are allowed while subscribed";
+                        break Closed::Invalid(String::from(reason));
+                    }
+                    // subscribers are expected to stay quiet
+                    Err(ProtocolError::Io(e)) if is_timeout(&e) => continue,
+                    Err(e) if !e.is_fatal() => break Closed::Invalid(e.to_string()),
+                    Ok(None) | Err(_) => break Closed::Disconnected,
+                }
+            };
+            subscriber.close(reason);
+        });
+
+        loop {
+            let result = match subscriber.next() {
+                Event::Message(message) => protocol::write_cast(writer, topic, &message),
+                Event::Lost(count) => protocol::write_lost(writer, count),
+                Event::Closed(reason) => {
+                    if reason == Closed::TooSlow {
+                        // the connection is closed after this, stop waiting
+                        // for requests
+                        let _ = stream.shutdown(Shutdown::Read);
+                    }
+                    return Ok(reason);
+                }
+            };
+            if let Err(e) = result.and_then(|_| writer.flush()) {
+                subscriber.close(Closed::Disconnected);
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        }
+    })
+}
+
+/// Send the status line ending a response to `SUB`; returns whether the
+/// connection should carry on
+fn end_subscription(writer: &mut impl Write, closed: Closed) -> io::Result<bool> {
+    match closed {
+        Closed::Unsubscribed => protocol::write_ok(writer, "")?,
+        Closed::Quit => {
+            protocol::write_ok(writer, "BYE")?;
+            return Ok(false);
+        }
+        Closed::Disconnected => return Ok(false),
+        Closed::TooSlow => {
+            let message = "subscriber did not keep up, disconnecting";
+            protocol::write_err(writer, ErrorCode::TooSlow, message)?;
+            return Ok(false);
+        }
+        Closed::ShuttingDown => {
+            protocol::write_err(writer, ErrorCode::Unavailable, "server is shutting down")?;
+            return Ok(false);
+        }
+        Closed::Invalid(reason) => protocol::write_err(writer, ErrorCode::BadRequest, &reason)?,
+    }
+    Ok(true)
+}
+
+/// Handle a single request, writing the response to `writer`; returns whether
+/// the connection should carry on
+///
+/// Failures of storage are reported to the client, only failures to write
+/// the response are returned as errors.
+fn handle_request(
+    request: Request,
+    broker: &Broker,
+    config: &Config,
+    principal: Option<&str>,
+    stream: &Stream,
+    writer: &mut impl Write,
+) -> io::Result<bool> {
+    let result = match request {
+        Request::Ping => protocol::write_ok(writer, "PONG"),
+        Request::Publish {
+            mailbox,
+            message,
+            options,
+        } => match broker.publish(mailbox, message, options) {
+            Ok(Some(id)) => protocol::write_ok(writer, &id.to_string()),
+            Ok(None) => protocol::write_err(writer, ErrorCode::Full, "mailbox is full"),
+            Err(e) => storage_failure(writer, e),
+        },
+        Request::PublishBatch { mailbox, messages } => {
+            match broker.publish_batch(mailbox, messages) {
+                // IDs of a batch are consecutive
+                Ok(Some(ids)) => match (ids.first(), ids.last()) {
+                    (Some(first), Some(last)) => {
+                        protocol::write_ok(writer, &format!("{} {}", first, last))
+                    }
+                    _ => unreachable!("batches have at least one message"),
+                },
+                Ok(None) => protocol::write_err(writer, ErrorCode::Full, "mailbox is full"),
+                Err(e) => storage_failure(writer, e),
+            }
+        }
+        Request::Retrieve {
+            mailbox,
+            wait,
+            lease,
+            batch,
+        } => {
+            let visibility = lease.unwrap_or(config.visibility_timeout);
+            // no data items before the status means no message
+            let connected = || is_connected(stream.tcp());
+            for message in broker.retrieve(&mailbox, wait, visibility, batch, connected) {
+                protocol::write_message(writer, &message)?;
+            }
+            protocol::write_ok(writer, "")
+        }
+        Request::Ack { mailbox, id } => {
+            let result = broker.lock().ack(&mailbox, id);
+            acknowledged(writer, id, result)
+        }
+        Request::Nack { mailbox, id } => acknowledged(writer, id, broker.nack(&mailbox, id)),
+        Request::Cast { topic, message } => {
+            let subscribers = broker.topics().cast(&topic, message);
+            protocol::write_ok(writer, &subscribers.to_string())
+        }
+        Request::Auth(_) | Request::Subscribe { .. } => {
+            unreachable!("authentication and subscriptions are handled by handle_client")
+        }
+        Request::Unsubscribe => {
+            protocol::write_err(writer, ErrorCode::BadRequest, "not subscribed to a topic")
+        }
+        Request::List => {
+            let mailboxes = broker.lock().list();
+            let visible = mailboxes
+                .into_iter()
+                .filter(|(name, ..)| config.auth.is_allowed(principal, Permission::Admin, name));
+            for (name, depth, in_flight) in visible {
+                protocol::write_mailbox(writer, &name, depth, in_flight)?;
+            }
+            protocol::write_ok(writer, "")
+        }
+        Request::Stats => {
+            for family in metrics::collect(broker) {
+                // samples about a mailbox are only shown to those who may
+                // administer it, like in `LIST`
+                let visible = family.samples.iter().filter(|sample| {
+                    sample.label_value("mailbox").is_none_or(|mailbox| {
+                        config
+                            .auth
+                            .is_allowed(principal, Permission::Admin, mailbox)
+                    })
+                });
+                for sample in visible {
+                    protocol::write_stat(writer, sample)?;
+                }
+            }
+            protocol::write_ok(writer, "")
+        }
+        Request::Peek { mailbox, max } => {
+            let messages = broker.lock().peek(&mailbox, max);
+            for message in &messages {
+                protocol::write_message(writer, message)?;
+            }
+            protocol::write_ok(writer, "")
+        }
+        Request::Count { mailbox } => {
+            let (messages, bytes) = broker.lock().size(&mailbox);
+            protocol::write_ok(writer, &format!("{} {}", messages, bytes))
+        }
+        Request::Purge { mailbox } => {
+            let result = broker.lock().purge(&mailbox);
+            match result {
+                Ok(purged) => protocol::write_ok(writer, &purged.to_string()),
+                Err(e) => storage_failure(writer, e),
+            }
+        }
+        Request::Delete { mailbox, id } => {
+            let result = broker.lock().delete(&mailbox, id);
+            match result {
+                Ok(true) => protocol::write_ok(writer, ""),
+                Ok(false) => {
+                    let message = format!("message {} is not in {}", id, mailbox);
+                    protocol::write_err(writer, ErrorCode::NotFound, &message)
+                }
+                Err(e) => storage_failure(writer, e),
+            }
+        }
+        Request::Quit => {
+            protocol::write_ok(writer, "BYE")?;
+            return Ok(false);
+        }
+    };
+    result.map(|_| true)
+}
+
+/// Respond to `AUTH`, changing who the client is known as; failing to
+/// authenticate leaves the client unauthenticated, and disconnected once it
+/// failed too often
+fn authenticate(
+    auth: &Auth,
+    credentials: &Credentials,
+    peer: SocketAddr,
+    (principal, failures): (&mut Option<String>, &mut u32),
+    writer: &mut impl Write,
+) -> io::Result<bool> {
+    if !auth.is_enabled() {
+        let message = "authentication is not enabled";
+        protocol::write_err(writer, ErrorCode::BadRequest, message)?;
+        return Ok(true);
+    }
+    *principal = auth.authenticate(credentials).map(String::from);
+    match principal {
+        Some(principal) => {
+            info!(peer:% = peer, principal:% = principal; "Client authenticated");
+            protocol::write_ok(writer, principal)?;
+        }
+        None => {
+            warn!(peer:% = peer; "Client failed to authenticate");
+            *failures += 1;
+            if *failures >= MAX_AUTH_FAILURES {
+                let message = "invalid credentials, too many failed attempts";
+                protocol::write_err(writer, ErrorCode::Unauthorized, message)?;
+                return Ok(false);
+            }
+            protocol::write_err(writer, ErrorCode::Unauthorized, "invalid credentials")?;
+        }
+    }
+    Ok(true)
+}
+
+/// Respond to an `ACK` or `NACK` request
+fn acknowledged(writer: &mut impl Write, id: u64, result: io::Result<bool>) -> io::Result<()> {
+    match result {
+        Ok(true) => protocol::write_ok(writer, ""),
+        Ok(false) => {
+            let message = format!("message {} is not in flight", id);
+            protocol::write_err(writer, ErrorCode::NotFound, &message)
+        }
+        Err(e) => storage_failure(writer, e),
+    }
+}
+
+/// Log a storage failure and report it to the client
+fn storage_failure(writer: &mut impl Write, e: io::Error) -> io::Result<()> {
+    let e = Error::Storage(e);
+    error!("{}", e);
+    protocol::write_err(writer, ErrorCode::Internal, &e.to_string())
+}
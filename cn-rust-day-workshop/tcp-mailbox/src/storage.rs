@@ -0,0 +1,892 @@
+//! Mailboxes and the messages waiting in them
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::journal::{Compacted, Compaction, Journal, Record};
+use crate::protocol::MAX_MAILBOX_LENGTH;
+
+/// Journals are only compacted once they have at least this many records,
+/// compacting small ones isn't worth the effort
+const MIN_COMPACTION_RECORDS: u64 = 1000;
+
+/// Suffix added to a mailbox name to get the name of its dead-letter mailbox
+pub const DEAD_LETTER_SUFFIX: &str = ".dead";
+
+/// Limits applied to storage
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// messages delivered this many times without being acknowledged are
+    /// moved to a dead-letter mailbox
+    pub max_deliveries: u32,
+    /// maximum number of messages in a mailbox, including those in flight
+    pub max_queue_depth: Option<usize>,
+}
+
+/// What a publisher can give a message besides its body
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PublishOptions {
+    /// how long the message may wait in its mailbox, once it can be
+    /// delivered, before it expires without being delivered
+    pub ttl: Option<Duration>,
+    /// messages with higher priority are delivered first
+    pub priority: u8,
+    /// how long after being published the message can first be delivered
+    pub delay: Option<Duration>,
+    /// name and value pairs passed on to consumers as they are, e.g.
+    /// `content-type`
+    pub headers: Vec<(String, String)>,
+}
+
+/// How many messages a consumer takes at once
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Batch {
+    /// maximum number of messages
+    pub max: usize,
+    /// maximum size of the bodies of the messages altogether, though the
+    /// first message is taken whatever its size
+    pub bytes: Option<usize>,
+}
+
+impl Default for Batch {
+    /// A single message
+    fn default() -> Self {
+        Batch {
+            max: 1,
+            bytes: None,
+        }
+    }
+}
+
+/// A message stored in a mailbox
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Message {
+    /// storage-wide unique and increasing ID
+    pub id: u64,
+    /// when the message was published
+    pub published: SystemTime,
+    /// when the message expires unless delivered by then
+    pub expires: Option<SystemTime>,
+    pub priority: u8,
+    /// when the message can first be delivered, if it was delayed
+    pub deliver_after: Option<SystemTime>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// number of times the message was delivered to a consumer
+    pub deliveries: u32,
+}
+
+impl Message {
+    /// A message published now, to be given its ID once stored
+    fn new(body: Vec<u8>, options: PublishOptions, published: SystemTime) -> Self {
+        let deliver_after = options.delay.map(|delay| published + delay);
+        Message {
+            id: 0,
+            published,
+            // the TTL starts once the message can be delivered
+            expires: options
+                .ttl
+                .map(|ttl| deliver_after.unwrap_or(published) + ttl),
+            priority: options.priority,
+            deliver_after,
+            headers: options.headers,
+            body,
+            deliveries: 0,
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Messages in a mailbox: those ready to be delivered, highest priority first
+/// and in the order they were published within a priority, and those delayed
+/// until a later time, soonest first
+///
+/// Both are kept sorted, so that neither publishing nor retrieving has to go
+/// through all messages. Messages which expire are also indexed by when they
+/// do, so that discarding expired messages only goes through those due.
+#[derive(Debug, Default)]
+struct Queue {
+    ready: BTreeMap<(Reverse<u8>, u64), Message>,
+    delayed: BTreeMap<(SystemTime, u64), Message>,
+    /// priority and delay of messages which expire, by expiry and ID, to
+    /// find them in `ready` or `delayed`
+    expiring: BTreeMap<(SystemTime, u64), (u8, Option<SystemTime>)>,
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        self.ready.len() + self.delayed.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Message> {
+        self.ready.values().chain(self.delayed.values())
+    }
+
+    /// Add a message, in the position given by its priority and ID
+    fn insert(&mut self, message: Message, now: SystemTime) {
+        if let Some(expires) = message.expires {
+            let position = (message.priority, message.deliver_after);
+            self.expiring.insert((expires, message.id), position);
+        }
+        match message.deliver_after {
+            Some(after) if after > now => {
+                self.delayed.insert((after, message.id), message);
+            }
+            _ => {
+                self.ready
+                    .insert((Reverse(message.priority), message.id), message);
+            }
+        }
+    }
+
+    /// Take the next message to deliver, discarding expired ones on the way
+    /// and counting them in `expired`
+    fn pop(&mut self, now: SystemTime, expired: &mut u64) -> Option<Message> {
+        // make delayed messages which are due ready
+        while let Some(entry) = self.delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let message = entry.remove();
+            self.ready
+                .insert((Reverse(message.priority), message.id), message);
+        }
+        loop {
+            let (_, message) = self.ready.pop_first()?;
+            self.unindex(&message);
+            if !message.is_expired(now) {
+                return Some(message);
+            }
+            *expired += 1;
+        }
+    }
+
+    /// Remove a message taken out of the queue from the expiry index
+    fn unindex(&mut self, message: &Message) {
+        if let Some(expires) = message.expires {
+            self.expiring.remove(&(expires, message.id));
+        }
+    }
+
+    /// When the next delayed message can be delivered
+    fn next_due(&self) -> Option<SystemTime> {
+        self.delayed.keys().next().map(|&(after, _)| after)
+    }
+
+    /// Take a message out by ID, wherever it is
+    fn remove(&mut self, id: u64) -> Option<Message> {
+        let ready = self.ready.keys().find(|&&(_, i)| i == id).copied();
+        let message = match ready {
+            Some(key) => self.ready.remove(&key),
+            None => {
+                let delayed = self.delayed.keys().find(|&&(_, i)| i == id).copied()?;
+                self.delayed.remove(&delayed)
+            }
+        }?;
+        self.unindex(&message);
+        Some(message)
+    }
+
+    /// Discard messages which expired by `now`; returns the number of
+    /// messages discarded
+    fn discard_expired(&mut self, now: SystemTime) -> usize {
+        let mut discarded = 0;
+        while let Some(entry) = self.expiring.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let ((_, id), (priority, after)) = entry.remove_entry();
+            // delayed messages which became due are only made ready once
+            // retrieving gets to them
+            let delayed = after.and_then(|after| self.delayed.remove(&(after, id)));
+            if delayed
+                .or_else(|| self.ready.remove(&(Reverse(priority), id)))
+                .is_some()
+            {
+                discarded += 1;
+            }
+        }
+        discarded
+    }
+}
+
+/// Milliseconds since the Unix epoch, as timestamps are sent to clients and
+/// recorded in the journal
+pub fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+pub fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Number of messages storage dealt with since the server started
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub published: u64,
+    /// deliveries to consumers, including redeliveries
+    pub retrieved: u64,
+    pub acknowledged: u64,
+    /// messages discarded as they expired before being delivered
+    pub expired: u64,
+    pub dead_lettered: u64,
+    /// messages deleted or purged by administrators
+    pub deleted: u64,
+}
+
+/// A message delivered to a consumer which wasn't acknowledged yet
+#[derive(Debug)]
+struct Lease {
+    mailbox: String,
+    message: Message,
+    /// when the message goes back to its mailbox unless acknowledged
+    expires: Instant,
+}
+
+/// Mailboxes by name, each one being a queue of messages, optionally backed
+/// by a journal on disk
+///
+/// Retrieved messages aren't removed straight away but leased to the
+/// consumer, which has to acknowledge them before the lease expires,
+/// otherwise they go back to the mailbox to be delivered again.
+pub struct Storage {
+    mailboxes: HashMap<String, Queue>,
+    /// messages delivered to consumers, by ID
+    in_flight: HashMap<u64, Lease>,
+    /// number of messages in flight from each mailbox, so that the depth of
+    /// a mailbox doesn't take going through all of them
+    leased: HashMap<String, usize>,
+    /// ID of the next published message
+    next_id: u64,
+    journal: Option<Journal>,
+    limits: Limits,
+    /// tickets of consumers waiting for messages in each mailbox, in the
+    /// order they started waiting
+    waiting: HashMap<String, VecDeque<u64>>,
+    /// ticket given to the next waiting consumer
+    next_ticket: u64,
+    counts: Counts,
+}
+
+impl Storage {
+    /// Construct storage which only keeps messages in memory
+    pub fn new(limits: Limits) -> Self {
+        Storage {
+            mailboxes: HashMap::new(),
+            in_flight: HashMap::new(),
+            leased: HashMap::new(),
+            next_id: 1,
+            journal: None,
+            limits,
+            waiting: HashMap::new(),
+            next_ticket: 0,
+            counts: Counts::default(),
+        }
+    }
+
+    /// Construct storage backed by a journal, restoring messages recorded in
+    /// it which weren't acknowledged yet
+    ///
+    /// Messages which were in flight when the journal was last written to
+    /// go back to their mailboxes, to be delivered again. Messages which
+    /// expired meanwhile are left out.
+    pub fn with_journal(journal: Journal, records: Vec<Record>, limits: Limits) -> Self {
+        let mut storage = Storage::new(limits);
+        // messages by ID, so that deleting them doesn't have to go through
+        // the whole mailbox
+        let mut live = HashMap::new();
+        for record in records {
+            match record {
+                Record::Publish { mailbox, message } => {
+                    storage.next_id = storage.next_id.max(message.id + 1);
+                    live.insert(message.id, (mailbox, message));
+                }
+                Record::Delete { id, .. } => {
+                    live.remove(&id);
+                }
+                Record::Requeue { id, deliveries, .. } => {
+                    if let Some((_, message)) = live.get_mut(&id) {
+                        message.deliveries = deliveries;
+                    }
+                }
+                Record::NextId(id) => storage.next_id = storage.next_id.max(id),
+                Record::Batch(_) => unreachable!("batches are flattened by Journal::open"),
+            }
+        }
+        let now = SystemTime::now();
+        for (mailbox, message) in live.into_values() {
+            if !message.is_expired(now) {
+                storage
+                    .mailboxes
+                    .entry(mailbox)
+                    .or_default()
+                    .insert(message, now);
+            }
+        }
+        storage.journal = Some(journal);
+        storage
+    }
+
+    /// Add a message to the back of a mailbox, creating the mailbox if it
+    /// doesn't exist yet; returns ID of the message, or None if the mailbox
+    /// is full
+    pub fn publish(
+        &mut self,
+        mailbox: String,
+        body: Vec<u8>,
+        options: PublishOptions,
+    ) -> io::Result<Option<u64>> {
+        if !self.has_room(&mailbox, 1) {
+            return Ok(None);
+        }
+        let message = Message::new(body, options, SystemTime::now());
+        let id = self.append(mailbox, message)?;
+        self.counts.published += 1;
+        Ok(Some(id))
+    }
+
+    /// Add messages to the back of a mailbox, all of them or none; returns
+    /// IDs of the messages, which are consecutive, or None if the mailbox
+    /// doesn't have room for all of them
+    ///
+    /// The messages are written to the journal as a single batch, so that
+    /// they're restored either all or none after a crash.
+    pub fn publish_batch(
+        &mut self,
+        mailbox: String,
+        messages: Vec<(Vec<u8>, PublishOptions)>,
+    ) -> io::Result<Option<Vec<u64>>> {
+        if !self.has_room(&mailbox, messages.len()) {
+            return Ok(None);
+        }
+        let published = SystemTime::now();
+        let messages: Vec<_> = messages
+            .into_iter()
+            .zip(self.next_id..)
+            .map(|((body, options), id)| Message {
+                id,
+                ..Message::new(body, options, published)
+            })
+            .collect();
+        if let Some(journal) = &mut self.journal {
+            let records = messages
+                .iter()
+                .map(|message| Record::Publish {
+                    mailbox: mailbox.clone(),
+                    message: message.clone(),
+                })
+                .collect();
+            journal.append(&Record::Batch(records))?;
+        }
+        self.next_id += messages.len() as u64;
+        self.counts.published += messages.len() as u64;
+        let ids = messages.iter().map(|message| message.id).collect();
+        let queue = self.mailboxes.entry(mailbox).or_default();
+        for message in messages {
+            queue.insert(message, published);
+        }
+        Ok(Some(ids))
+    }
+
+    /// Whether a mailbox has room for this many more messages
+    fn has_room(&self, mailbox: &str, messages: usize) -> bool {
+        self.limits
+            .max_queue_depth
+            .is_none_or(|max| self.depth(mailbox) + messages <= max)
+    }
+
+    /// Number of messages in a mailbox, including those in flight
+    fn depth(&self, mailbox: &str) -> usize {
+        let waiting = self.mailboxes.get(mailbox).map_or(0, Queue::len);
+        let in_flight = self.leased.get(mailbox).copied().unwrap_or_default();
+        waiting + in_flight
+    }
+
+    /// Add a newly published message to the back of a mailbox, giving it the
+    /// next ID
+    fn append(&mut self, mailbox: String, mut message: Message) -> io::Result<u64> {
+        let id = self.next_id;
+        message.id = id;
+        if let Some(journal) = &mut self.journal {
+            journal.append(&Record::Publish {
+                mailbox: mailbox.clone(),
+                message: message.clone(),
+            })?;
+        }
+        self.next_id += 1;
+        self.mailboxes
+            .entry(mailbox)
+            .or_default()
+            .insert(message, SystemTime::now());
+        Ok(id)
+    }
+
+    /// Deliver the message with the highest priority which can be delivered
+    /// from a mailbox, if there is one, leasing it to the consumer for
+    /// `visibility` time; expired messages found on the way are discarded
+    pub fn retrieve(&mut self, mailbox: &str, visibility: Duration) -> Option<Message> {
+        self.retrieve_batch(mailbox, visibility, Batch::default())
+            .pop()
+    }
+
+    /// Deliver messages from a mailbox in the order `retrieve` would, up to
+    /// the limits of the batch, leasing them to the consumer for
+    /// `visibility` time
+    pub fn retrieve_batch(
+        &mut self,
+        mailbox: &str,
+        visibility: Duration,
+        batch: Batch,
+    ) -> Vec<Message> {
+        let queue = match self.mailboxes.get_mut(mailbox) {
+            Some(queue) => queue,
+            None => return Vec::new(),
+        };
+        let now = SystemTime::now();
+        let mut messages = Vec::new();
+        let mut bytes = 0;
+        while messages.len() < batch.max {
+            let message = match queue.pop(now, &mut self.counts.expired) {
+                Some(message) => message,
+                None => break,
+            };
+            bytes += message.body.len();
+            if !messages.is_empty() && batch.bytes.is_some_and(|max| bytes > max) {
+                // doesn't fit, leave it first in line
+                queue.insert(message, now);
+                break;
+            }
+            messages.push(message);
+        }
+
+        let expires = Instant::now() + visibility;
+        for message in &mut messages {
+            message.deliveries += 1;
+            self.lease(Lease {
+                mailbox: String::from(mailbox),
+                message: message.clone(),
+                expires,
+            });
+        }
+        self.counts.retrieved += messages.len() as u64;
+        messages
+    }
+
+    /// Acknowledge a delivered message, removing it for good; returns false
+    /// if the message isn't in flight from given mailbox
+    pub fn ack(&mut self, mailbox: &str, id: u64) -> io::Result<bool> {
+        let lease = match self.take_lease(mailbox, id) {
+            Some(lease) => lease,
+            None => return Ok(false),
+        };
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.append(&Record::Delete {
+                id,
+                mailbox: lease.mailbox.clone(),
+            }) {
+                // keep the lease so the acknowledgement can be retried
+                self.lease(lease);
+                return Err(e);
+            }
+        }
+        self.counts.acknowledged += 1;
+        Ok(true)
+    }
+
+    /// Reject a delivered message, returning it to its mailbox straight away;
+    /// returns false if the message isn't in flight from given mailbox
+    pub fn nack(&mut self, mailbox: &str, id: u64) -> io::Result<bool> {
+        match self.in_flight.get(&id) {
+            Some(lease) if lease.mailbox == mailbox => self.requeue(id).map(|_| true),
+            _ => Ok(false),
+        }
+    }
+
+    fn take_lease(&mut self, mailbox: &str, id: u64) -> Option<Lease> {
+        match self.in_flight.get(&id) {
+            Some(lease) if lease.mailbox == mailbox => self.release(id),
+            _ => None,
+        }
+    }
+
+    /// Put a message in flight
+    fn lease(&mut self, lease: Lease) {
+        *self.leased.entry(lease.mailbox.clone()).or_default() += 1;
+        self.in_flight.insert(lease.message.id, lease);
+    }
+
+    /// Take a message out of flight, returning its lease
+    fn release(&mut self, id: u64) -> Option<Lease> {
+        let lease = self.in_flight.remove(&id)?;
+        if let Some(count) = self.leased.get_mut(&lease.mailbox) {
+            *count -= 1;
+            if *count == 0 {
+                self.leased.remove(&lease.mailbox);
+            }
+        }
+        Some(lease)
+    }
+
+    /// Return messages with expired leases to their mailboxes; returns the
+    /// number of messages which became available again
+    pub fn requeue_expired(&mut self) -> io::Result<usize> {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, lease)| lease.expires <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in &expired {
+            // leases which can't be given up stay in flight, so they're
+            // tried again next time
+            self.requeue(id)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Put a message in flight back in its mailbox, in the position given by
+    /// its priority and ID, or move it to the dead-letter mailbox if it was
+    /// delivered too many times already; messages which expired meanwhile
+    /// are discarded
+    ///
+    /// The lease is only given up once the change is journaled, so that
+    /// nothing is lost if that fails.
+    fn requeue(&mut self, id: u64) -> io::Result<()> {
+        let lease = match self.in_flight.get(&id) {
+            Some(lease) => lease,
+            None => return Ok(()),
+        };
+        if lease.message.is_expired(SystemTime::now()) {
+            // not worth a journal record, replaying the journal leaves out
+            // expired messages anyway
+            self.release(id);
+            self.counts.expired += 1;
+            return Ok(());
+        }
+
+        let dead_letters = match lease.message.deliveries >= self.limits.max_deliveries {
+            true => dead_letters_for(&lease.mailbox),
+            false => None,
+        };
+        if let Some(dead_letters) = dead_letters {
+            warn!(
+                id,
+                mailbox = lease.mailbox.as_str(),
+                deliveries = lease.message.deliveries;
+                "Message delivered too many times, moving to dead letters"
+            );
+            // dead letters are kept even if the mailbox is full, and until
+            // someone deals with them, rather than lost
+            let message = Message {
+                expires: None,
+                deliver_after: None,
+                ..lease.message.clone()
+            };
+            if let Some(journal) = &mut self.journal {
+                // written at once, so the message can't end up in both
+                // mailboxes or in neither
+                journal.append(&Record::Batch(vec![
+                    Record::Delete {
+                        id,
+                        mailbox: lease.mailbox.clone(),
+                    },
+                    Record::Publish {
+                        mailbox: dead_letters.clone(),
+                        message: message.clone(),
+                    },
+                ]))?;
+            }
+            self.release(id);
+            self.mailboxes
+                .entry(dead_letters)
+                .or_default()
+                .insert(message, SystemTime::now());
+            self.counts.dead_lettered += 1;
+            return Ok(());
+        }
+
+        if let Some(journal) = &mut self.journal {
+            // so that the number of deliveries counts towards the limit
+            // after a restart
+            journal.append(&Record::Requeue {
+                id,
+                mailbox: lease.mailbox.clone(),
+                deliveries: lease.message.deliveries,
+            })?;
+        }
+        if let Some(lease) = self.release(id) {
+            self.mailboxes
+                .entry(lease.mailbox)
+                .or_default()
+                .insert(lease.message, SystemTime::now());
+        }
+        Ok(())
+    }
+
+    /// Discard messages which expired before being delivered; returns the
+    /// number of messages discarded
+    pub fn discard_expired(&mut self) -> usize {
+        let now = SystemTime::now();
+        let discarded = self
+            .mailboxes
+            .values_mut()
+            .map(|queue| queue.discard_expired(now))
+            .sum::<usize>();
+        self.counts.expired += discarded as u64;
+        discarded
+    }
+
+    /// Messages waiting in a mailbox, in the order they'd be delivered in,
+    /// up to `max` of them; nothing is delivered or changed
+    ///
+    /// Delayed messages come last, in the order they become due, and expired
+    /// messages which weren't discarded yet are left out.
+    pub fn peek(&self, mailbox: &str, max: usize) -> Vec<Message> {
+        let now = SystemTime::now();
+        self.mailboxes
+            .get(mailbox)
+            .into_iter()
+            .flat_map(Queue::iter)
+            .filter(|m| !m.is_expired(now))
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    /// Number of messages waiting in a mailbox, not counting those in
+    /// flight, along with the size of their bodies in bytes
+    ///
+    /// Like in `peek`, expired messages which weren't discarded yet aren't
+    /// counted.
+    pub fn size(&self, mailbox: &str) -> (usize, usize) {
+        let now = SystemTime::now();
+        self.mailboxes
+            .get(mailbox)
+            .into_iter()
+            .flat_map(Queue::iter)
+            .filter(|m| !m.is_expired(now))
+            .fold((0, 0), |(messages, bytes), m| {
+                (messages + 1, bytes + m.body.len())
+            })
+    }
+
+    /// Remove every message from a mailbox, including those in flight;
+    /// returns the number of messages removed
+    ///
+    /// Consumers acknowledging messages which were in flight find them gone.
+    pub fn purge(&mut self, mailbox: &str) -> io::Result<usize> {
+        let leased: Vec<_> = self
+            .in_flight
+            .iter()
+            .filter(|(_, lease)| lease.mailbox == mailbox)
+            .map(|(&id, _)| id)
+            .collect();
+        let waiting = self.mailboxes.get(mailbox);
+        let ids: Vec<_> = waiting
+            .into_iter()
+            .flat_map(Queue::iter)
+            .map(|m| m.id)
+            .chain(leased.iter().copied())
+            .collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        if let Some(journal) = &mut self.journal {
+            let records = ids
+                .iter()
+                .map(|&id| Record::Delete {
+                    id,
+                    mailbox: String::from(mailbox),
+                })
+                .collect();
+            journal.append(&Record::Batch(records))?;
+        }
+        self.mailboxes.remove(mailbox);
+        for &id in &leased {
+            self.release(id);
+        }
+        self.counts.deleted += ids.len() as u64;
+        Ok(ids.len())
+    }
+
+    /// Remove a message from a mailbox, whether it's waiting or in flight;
+    /// returns false if there's no such message in the mailbox
+    pub fn delete(&mut self, mailbox: &str, id: u64) -> io::Result<bool> {
+        let waiting = self
+            .mailboxes
+            .get(mailbox)
+            .and_then(|q| q.iter().find(|m| m.id == id));
+        let leased = self
+            .in_flight
+            .get(&id)
+            .filter(|lease| lease.mailbox == mailbox);
+        if waiting.is_none() && leased.is_none() {
+            return Ok(false);
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.append(&Record::Delete {
+                id,
+                mailbox: String::from(mailbox),
+            })?;
+        }
+        if let Some(queue) = self.mailboxes.get_mut(mailbox) {
+            queue.remove(id);
+        }
+        self.take_lease(mailbox, id);
+        self.counts.deleted += 1;
+        Ok(true)
+    }
+
+    /// When the next delayed message in a mailbox can be delivered, if there
+    /// are any
+    pub fn next_due(&self, mailbox: &str) -> Option<SystemTime> {
+        self.mailboxes.get(mailbox).and_then(Queue::next_due)
+    }
+
+    /// Whether any consumers are waiting for messages in a mailbox
+    pub fn has_waiting(&self, mailbox: &str) -> bool {
+        self.waiting.get(mailbox).is_some_and(|q| !q.is_empty())
+    }
+
+    /// Queue up a consumer waiting for messages in a mailbox, returns a
+    /// ticket identifying it
+    pub fn start_waiting(&mut self, mailbox: &str) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiting
+            .entry(String::from(mailbox))
+            .or_default()
+            .push_back(ticket);
+        ticket
+    }
+
+    /// Whether the consumer with given ticket is first in line for messages
+    pub fn is_next_waiting(&self, mailbox: &str, ticket: u64) -> bool {
+        self.waiting
+            .get(mailbox)
+            .and_then(|q| q.front())
+            .is_some_and(|&t| t == ticket)
+    }
+
+    /// Remove a consumer from the queue of those waiting for messages
+    pub fn stop_waiting(&mut self, mailbox: &str, ticket: u64) {
+        if let Some(queue) = self.waiting.get_mut(mailbox) {
+            queue.retain(|&t| t != ticket);
+            if queue.is_empty() {
+                self.waiting.remove(mailbox);
+            }
+        }
+    }
+
+    pub fn counts(&self) -> Counts {
+        self.counts
+    }
+
+    /// Names of all mailboxes along with the number of messages waiting in
+    /// each and the number of messages in flight from each
+    pub fn list(&self) -> Vec<(String, usize, usize)> {
+        let mut mailboxes: Vec<_> = self
+            .mailboxes
+            .iter()
+            .map(|(name, queue)| {
+                let leased = self.leased.get(name).copied();
+                (name.clone(), queue.len(), leased.unwrap_or_default())
+            })
+            .collect();
+        mailboxes.sort();
+        mailboxes
+    }
+
+    /// Make sure all changes are on disk before the server stops; messages
+    /// are lost if there's no journal to keep them in
+    pub fn shut_down(&mut self) -> io::Result<()> {
+        match &mut self.journal {
+            Some(journal) => journal.sync(),
+            None => {
+                let messages =
+                    self.mailboxes.values().map(Queue::len).sum::<usize>() + self.in_flight.len();
+                if messages > 0 {
+                    warn!(
+                        messages;
+                        "Discarding messages kept in memory, configure a journal to keep them"
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Periodic journal maintenance: sync pending writes and start compacting
+    /// the journal once most of its records refer to acknowledged messages;
+    /// the compaction is written out without holding up storage, then
+    /// finished with `finish_compaction`
+    pub fn maintain(&mut self) -> io::Result<Option<Compaction>> {
+        let journal = match &mut self.journal {
+            Some(journal) => journal,
+            None => return Ok(None),
+        };
+        journal.sync_if_due()?;
+
+        let live = self.mailboxes.values().map(Queue::len).sum::<usize>() + self.in_flight.len();
+        let records = journal.records();
+        if records >= MIN_COMPACTION_RECORDS && records > 2 * live as u64 {
+            // messages in flight weren't acknowledged yet, so they need to be
+            // kept as well
+            let mut messages: Vec<_> = self
+                .mailboxes
+                .iter()
+                .flat_map(|(name, queue)| queue.iter().map(move |m| (name, m)))
+                .chain(
+                    self.in_flight
+                        .values()
+                        .map(|lease| (&lease.mailbox, &lease.message)),
+                )
+                .collect();
+            // keep the original order, so IDs keep increasing in the journal
+            messages.sort_by_key(|(_, m)| m.id);
+            let live = messages.into_iter().map(|(name, m)| Record::Publish {
+                mailbox: name.clone(),
+                message: m.clone(),
+            });
+            // acknowledged messages are left out, so the next ID has to be
+            // recorded for their IDs not to be given out again
+            let next_id = std::iter::once(Record::NextId(self.next_id));
+            let compaction = journal.start_compaction(next_id.chain(live))?;
+            return Ok(Some(compaction));
+        }
+        Ok(None)
+    }
+
+    /// Replace the journal with a compacted one, see `maintain`
+    pub fn finish_compaction(&mut self, compacted: Compacted) -> io::Result<()> {
+        match &mut self.journal {
+            Some(journal) => journal.finish_compaction(compacted),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Name of the dead-letter mailbox for a mailbox, None if its messages stay
+/// where they are instead: dead letters themselves aren't moved again, and
+/// the name would be too long for some mailboxes
+fn dead_letters_for(mailbox: &str) -> Option<String> {
+    if mailbox.ends_with(DEAD_LETTER_SUFFIX)
+        || mailbox.len() + DEAD_LETTER_SUFFIX.len() > MAX_MAILBOX_LENGTH
+    {
+        return None;
+    }
+    Some(format!("{}{}", mailbox, DEAD_LETTER_SUFFIX))
+}
+
+/// How often `Storage::maintain` and `Storage::requeue_expired` should be
+/// called
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
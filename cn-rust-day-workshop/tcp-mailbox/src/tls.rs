@@ -0,0 +1,225 @@
+//! TLS for connections between clients and the server, using rustls
+//!
+//! Certificates and keys are read from PEM files.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig};
+use rustls::{ServerConnection, DEFAULT_VERSIONS};
+
+/// Amount of encrypted data read from the connection at once
+const READ_SIZE: usize = 4096;
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            invalid_data(format!(
+                "could not read certificates from {:?}: {}",
+                path, e
+            ))
+        })?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificates in {:?}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| invalid_data(format!("could not read private key from {:?}: {}", path, e)))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| invalid_data(format!("invalid CA certificate in {:?}: {}", path, e)))?;
+    }
+    Ok(roots)
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// TLS configuration of the server, presenting the certificate chain in
+/// `cert`, and requiring clients to present a certificate signed by a CA in
+/// `client_ca` if given
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(DEFAULT_VERSIONS)
+        .map_err(invalid_data)?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(client_ca)?),
+                provider(),
+            )
+            .build()
+            .map_err(invalid_data)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| invalid_data(format!("invalid certificate or key: {}", e)))?;
+    Ok(Arc::new(config))
+}
+
+/// TLS configuration of a client, trusting servers with a certificate
+/// signed by a CA in `ca`, and presenting the certificate chain and key in
+/// `identity` to servers requiring client certificates
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(DEFAULT_VERSIONS)
+        .map_err(invalid_data)?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid_data(format!("invalid certificate or key: {}", e)))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// A TLS connection, which can be read from and written to at the same time
+/// from different threads, like a `TcpStream`
+///
+/// Reading waits for encrypted data without holding on to the TLS state, so
+/// that writing isn't blocked meanwhile. The session is ended with a
+/// close_notify alert once the stream is dropped; peers disconnecting without
+/// one are reported as an unexpected end of file, as the data may have been
+/// cut short by an attacker.
+#[derive(Debug)]
+pub struct TlsStream {
+    tcp: TcpStream,
+    connection: Mutex<Connection>,
+}
+
+impl TlsStream {
+    /// Perform the server side of the handshake on an accepted connection
+    pub fn accept(tcp: TcpStream, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let connection = ServerConnection::new(config).map_err(invalid_data)?;
+        TlsStream::handshake(tcp, connection.into())
+    }
+
+    /// Perform the client side of the handshake, verifying the server's
+    /// certificate is valid for `name`
+    pub fn connect(
+        tcp: TcpStream,
+        config: Arc<ClientConfig>,
+        name: ServerName<'static>,
+    ) -> io::Result<Self> {
+        let connection = ClientConnection::new(config, name).map_err(invalid_data)?;
+        TlsStream::handshake(tcp, connection.into())
+    }
+
+    fn handshake(tcp: TcpStream, mut connection: Connection) -> io::Result<Self> {
+        while connection.is_handshaking() {
+            connection.complete_io(&mut &tcp)?;
+        }
+        Ok(TlsStream {
+            tcp,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Send whatever TLS records are waiting to be sent
+    fn send(&self, connection: &mut Connection) -> io::Result<()> {
+        while connection.wants_write() {
+            connection.write_tls(&mut &self.tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        let connection = self
+            .connection
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        connection.send_close_notify();
+        // the peer may well be gone already
+        while connection.wants_write() {
+            if connection.write_tls(&mut &self.tcp).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.lock().reader().read(buf) {
+                // no data available yet
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+
+            let mut records = [0; READ_SIZE];
+            let read = (&self.tcp).read(&mut records)?;
+            if read == 0 {
+                // a peer ending the session with close_notify is reported as
+                // the end of data by the reader above
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "peer disconnected without ending the TLS session",
+                ));
+            }
+            let mut connection = self.lock();
+            let mut records = &records[..read];
+            while !records.is_empty() {
+                connection.read_tls(&mut records)?;
+                let state = connection.process_new_packets();
+                // let the peer know what went wrong, e.g. with an alert
+                self.send(&mut connection)?;
+                state.map_err(invalid_data)?;
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.lock();
+        let written = connection.writer().write(buf)?;
+        self.send(&mut connection)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.lock();
+        connection.writer().flush()?;
+        self.send(&mut connection)
+    }
+}
@@ -0,0 +1,787 @@
+//! End-to-end tests of the server, each running its own server on a port
+//! picked by the OS and talking to it over TCP.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use tcp_mailbox::auth::Secret;
+use tcp_mailbox::client::{Client, ClientError, ClientOptions};
+use tcp_mailbox::config::Config;
+use tcp_mailbox::protocol::{Credentials, Wait, MAX_LINE_LENGTH};
+use tcp_mailbox::server::Server;
+use tcp_mailbox::storage::{Batch, Message, PublishOptions};
+
+fn start_with(config: Config) -> Server {
+    let config = Config {
+        listen: vec![String::from("127.0.0.1:0")],
+        ..config
+    };
+    Server::start(config).expect("failed to start server")
+}
+
+fn start() -> Server {
+    start_with(Config::default())
+}
+
+fn client(server: &Server) -> Client {
+    Client::connect(&server.local_addr().to_string()).expect("failed to connect")
+}
+
+/// Journal of a single test, removed when dropped
+struct TempJournal {
+    path: PathBuf,
+}
+
+impl TempJournal {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("tcp-mailbox-{}-{}", name, process::id()));
+        // left behind by a test run that was killed
+        let _ = fs::remove_file(&path);
+        TempJournal { path }
+    }
+
+    /// Server configuration keeping messages in the journal
+    fn config(&self) -> Config {
+        Config {
+            journal: Some(self.path.clone()),
+            ..Config::default()
+        }
+    }
+}
+
+impl Drop for TempJournal {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Raw connection, for sending requests the client wouldn't
+struct Raw {
+    reader: BufReader<TcpStream>,
+    stream: TcpStream,
+}
+
+impl Raw {
+    fn connect(server: &Server) -> Self {
+        let stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut raw = Raw {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream,
+        };
+        assert!(raw.line().starts_with("OK MAILBOX/"));
+        raw
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        self.stream.write_all(data).unwrap();
+    }
+
+    /// Next line sent by the server, empty once it disconnected
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line
+    }
+}
+
+#[test]
+fn retrieves_messages_in_order_published() {
+    let server = start();
+    let mut client = client(&server);
+    let ids: Vec<_> = (0..10)
+        .map(|i| client.publish("orders", format!("order {}", i).as_bytes()))
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+    for (i, id) in ids.into_iter().enumerate() {
+        let message = client.retrieve("orders", Wait::No).unwrap().unwrap();
+        assert_eq!(message.id, id);
+        assert_eq!(message.body, format!("order {}", i).into_bytes());
+        assert_eq!(message.deliveries, 1);
+        client.ack("orders", message.id).unwrap();
+    }
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_none());
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn client_rejects_names_that_would_change_the_request() {
+    let server = start();
+    let mut client = client(&server);
+    client.publish("orders", b"one").unwrap();
+
+    let invalid = |result| match result {
+        Err(ClientError::InvalidRequest(_)) => (),
+        result => panic!("unexpected result {:?}", result),
+    };
+    invalid(client.publish("orders\nPURGE orders", b"two").map(drop));
+    invalid(client.purge("orders priority=9").map(drop));
+    let options = PublishOptions {
+        headers: vec![(String::from("x"), String::from("1\nPURGE orders"))],
+        ..PublishOptions::default()
+    };
+    invalid(client.publish_with("orders", b"two", options).map(drop));
+
+    // nothing was sent, and the connection carries on
+    assert_eq!(client.count("orders").unwrap().0, 1);
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn retrieving_from_empty_mailbox() {
+    let server = start();
+    let mut consumer = client(&server);
+    assert!(consumer.retrieve("empty", Wait::No).unwrap().is_none());
+
+    let started = Instant::now();
+    let wait = Duration::from_millis(200);
+    assert!(consumer
+        .retrieve("empty", Wait::For(wait))
+        .unwrap()
+        .is_none());
+    assert!(started.elapsed() >= wait);
+
+    // a waiting consumer gets a message published meanwhile
+    let addr = server.local_addr().to_string();
+    let publisher = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        Client::connect(&addr)
+            .unwrap()
+            .publish("empty", b"finally")
+            .unwrap();
+    });
+    let message = consumer.retrieve("empty", Wait::Forever).unwrap().unwrap();
+    assert_eq!(message.body, b"finally");
+    publisher.join().unwrap();
+
+    // acknowledging a message that isn't in flight fails
+    match consumer.ack("empty", message.id + 1) {
+        Err(ClientError::Server { code: 404, .. }) => (),
+        result => panic!("unexpected result {:?}", result),
+    }
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn concurrent_publishers() {
+    const PUBLISHERS: usize = 8;
+    const MESSAGES: usize = 50;
+    let server = start();
+    let addr = server.local_addr().to_string();
+    let publishers: Vec<_> = (0..PUBLISHERS)
+        .map(|publisher| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let mut client = Client::connect(&addr).unwrap();
+                for i in 0..MESSAGES {
+                    let message = format!("{} {}", publisher, i);
+                    client.publish("shared", message.as_bytes()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+
+    // every message arrives once, and messages of each publisher in order
+    let mut consumer = client(&server);
+    let mut next = vec![0; PUBLISHERS];
+    let mut ids = HashSet::new();
+    while let Some(message) = consumer.retrieve("shared", Wait::No).unwrap() {
+        let body = String::from_utf8(message.body).unwrap();
+        let (publisher, i) = body.split_once(' ').unwrap();
+        let publisher: usize = publisher.parse().unwrap();
+        assert_eq!(i.parse::<usize>().unwrap(), next[publisher]);
+        next[publisher] += 1;
+        assert!(ids.insert(message.id));
+        consumer.ack("shared", message.id).unwrap();
+    }
+    assert_eq!(next, vec![MESSAGES; PUBLISHERS]);
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn disconnecting_mid_request() {
+    let server = start();
+
+    // a payload cut short isn't published
+    let mut raw = Raw::connect(&server);
+    raw.send(b"PUB orders 10\nhel");
+    drop(raw);
+    // nor is a request line cut short
+    let mut raw = Raw::connect(&server);
+    raw.send(b"PUB ord");
+    drop(raw);
+
+    let mut client = client(&server);
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_none());
+    client.publish("orders", b"complete").unwrap();
+    let message = client.retrieve("orders", Wait::No).unwrap().unwrap();
+    assert_eq!(message.body, b"complete");
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn disconnected_consumer_stops_waiting() {
+    // a message taken on behalf of the disconnected consumer would only be
+    // delivered again once its lease expires, long after the test is over
+    let server = start_with(Config {
+        visibility_timeout: Duration::from_secs(60),
+        ..Config::default()
+    });
+    let mut raw = Raw::connect(&server);
+    raw.send(b"GET orders wait\n");
+    drop(raw);
+    // let the server start waiting for a message on behalf of the consumer
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = client(&server);
+    client.publish("orders", b"hello").unwrap();
+    let message = client
+        .retrieve("orders", Wait::For(Duration::from_secs(5)))
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.body, b"hello");
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn waiting_clients_do_not_hold_up_workers() {
+    let server = start_with(Config {
+        workers: 1,
+        ..Config::default()
+    });
+    let mut consumer = Raw::connect(&server);
+    consumer.send(b"GET orders wait\n");
+    let mut subscriber = Raw::connect(&server);
+    subscriber.send(b"SUB news\n");
+    // an idle client doesn't hold up the worker either
+    let _idle = Raw::connect(&server);
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = client(&server);
+    client.ping().unwrap();
+    client.publish("orders", b"hello").unwrap();
+    assert!(consumer.line().starts_with("MSG 5 "));
+    assert_eq!(consumer.line(), "hello\n");
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn bounds_clients_served_at_once() {
+    let server = start_with(Config {
+        max_connections: 3,
+        ..Config::default()
+    });
+    // clients over the limit aren't accepted, so aren't greeted either
+    let greet = |waiting: &mut Vec<BufReader<TcpStream>>, served: &mut Vec<_>| {
+        for mut reader in std::mem::take(waiting) {
+            let timeout = Some(Duration::from_millis(50));
+            reader.get_ref().set_read_timeout(timeout).unwrap();
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(_) if line.starts_with("OK MAILBOX/") => served.push(reader),
+                _ => waiting.push(reader),
+            }
+        }
+    };
+    let mut waiting: Vec<_> = (0..20)
+        .map(|_| BufReader::new(TcpStream::connect(server.local_addr()).unwrap()))
+        .collect();
+    let mut served = Vec::new();
+    thread::sleep(Duration::from_millis(100));
+    greet(&mut waiting, &mut served);
+    assert_eq!(served.len(), 3);
+    assert_eq!(waiting.len(), 17);
+
+    // once a client disconnects, the next one is served
+    served.pop();
+    thread::sleep(Duration::from_millis(100));
+    greet(&mut waiting, &mut served);
+    assert_eq!(served.len(), 3);
+    assert_eq!(waiting.len(), 16);
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn shuts_down_promptly_with_clients_connected() {
+    // shutting down used to race with waking up accept loops, so try a few
+    // times
+    for _ in 0..10 {
+        let server = start();
+        let mut consumer = Raw::connect(&server);
+        consumer.send(b"GET orders wait\n");
+        let mut subscriber = Raw::connect(&server);
+        subscriber.send(b"SUB news\n");
+        let _idle = Raw::connect(&server);
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        server.shut_down().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(consumer.line(), "OK\n");
+        assert!(subscriber.line().starts_with("ERR 503 "));
+    }
+}
+
+#[test]
+fn oversized_requests() {
+    let server = start_with(Config {
+        max_message_size: 16,
+        ..Config::default()
+    });
+
+    // messages larger than the maximum are refused, and the connection
+    // closed as the payload can't be skipped reliably
+    let mut raw = Raw::connect(&server);
+    raw.send(b"PUB orders 17\n0123456789abcdefg\n");
+    assert!(raw.line().starts_with("ERR 413 "));
+    assert_eq!(raw.line(), "");
+
+    // so are request lines longer than the maximum
+    let mut raw = Raw::connect(&server);
+    let line = format!("GET {}\n", "x".repeat(MAX_LINE_LENGTH));
+    raw.send(line.as_bytes());
+    assert!(raw.line().starts_with("ERR 413 "));
+    assert_eq!(raw.line(), "");
+
+    // messages of exactly the maximum size are fine
+    let mut client = client(&server);
+    client.publish("orders", b"0123456789abcdef").unwrap();
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_some());
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn enforces_authentication_and_access_control() {
+    let auth = toml::from_str(
+        r#"
+        users = [{ name = "alice", password = "wonderland" }]
+        tokens = [{ name = "ci", token = "s3cret" }]
+
+        [[acl]]
+        principal = "ci"
+        mailboxes = "ci.*"
+        allow = ["publish"]
+
+        [[acl]]
+        principal = "alice"
+        mailboxes = "*"
+        allow = ["retrieve", "admin"]
+        "#,
+    )
+    .unwrap();
+    let server = start_with(Config {
+        auth,
+        ..Config::default()
+    });
+    let connect = |credentials: Credentials| {
+        let options = ClientOptions {
+            credentials: Some(credentials),
+            ..ClientOptions::default()
+        };
+        Client::with_options(&server.local_addr().to_string(), options)
+    };
+    let is_denied = |result, expected| match result {
+        Err(ClientError::Server { code, .. }) => code == expected,
+        _ => false,
+    };
+
+    // only harmless requests are allowed before authenticating
+    let mut raw = Raw::connect(&server);
+    raw.send(b"PING\nGET ci.jobs\n");
+    assert_eq!(raw.line(), "OK PONG\n");
+    assert_eq!(raw.line(), "ERR 401 authentication required\n");
+    raw.send(b"AUTH wrong\nAUTH s3cret\n");
+    assert_eq!(raw.line(), "ERR 401 invalid credentials\n");
+    assert_eq!(raw.line(), "OK ci\n");
+
+    // guessing credentials gets the client disconnected
+    let mut raw = Raw::connect(&server);
+    raw.send(b"AUTH guess\nAUTH guess\nAUTH guess\nAUTH s3cret\n");
+    assert_eq!(raw.line(), "ERR 401 invalid credentials\n");
+    assert_eq!(raw.line(), "ERR 401 invalid credentials\n");
+    assert!(raw.line().starts_with("ERR 401 "));
+    assert_eq!(raw.line(), "");
+
+    let wrong_password = Credentials::Password {
+        user: String::from("alice"),
+        password: String::from("s3cret"),
+    };
+    assert!(is_denied(connect(wrong_password).map(|_| ()), 401));
+
+    let mut ci = connect(Credentials::Token(String::from("s3cret"))).unwrap();
+    ci.publish("ci.jobs", b"build").unwrap();
+    assert!(is_denied(
+        ci.publish("orders", b"free stuff").map(|_| ()),
+        403
+    ));
+    assert!(is_denied(ci.retrieve("ci.jobs", Wait::No).map(|_| ()), 403));
+
+    let mut alice = connect(Credentials::Password {
+        user: String::from("alice"),
+        password: String::from("wonderland"),
+    })
+    .unwrap();
+    assert!(is_denied(
+        alice.publish("ci.jobs", b"sneaky").map(|_| ()),
+        403
+    ));
+    let message = alice.retrieve("ci.jobs", Wait::No).unwrap().unwrap();
+    assert_eq!(message.body, b"build");
+    alice.ack("ci.jobs", message.id).unwrap();
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn messages_carry_metadata_and_expire() {
+    let journal = TempJournal::new("metadata");
+    let config = || journal.config();
+    let server = start_with(config());
+    let mut client = client(&server);
+    let headers = vec![
+        (String::from("content-type"), String::from("text/plain")),
+        (String::from("correlation-id"), String::from("42")),
+    ];
+    let options = PublishOptions {
+        ttl: Some(Duration::from_secs(60)),
+        headers: headers.clone(),
+        ..PublishOptions::default()
+    };
+    let id = client.publish_with("orders", b"hello", options).unwrap();
+    let short_lived = PublishOptions {
+        ttl: Some(Duration::from_millis(100)),
+        ..PublishOptions::default()
+    };
+    client.publish_with("orders", b"gone", short_lived).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    // the expired message is left out whether it was discarded yet or not
+    assert_eq!(client.count("orders").unwrap(), (1, 5));
+    assert_eq!(client.peek("orders", 10).unwrap().len(), 1);
+    server.shut_down().unwrap();
+
+    // metadata survives a restart, and the expired message isn't delivered
+    let server = start_with(config());
+    let mut client = self::client(&server);
+    let message = client.retrieve("orders", Wait::No).unwrap().unwrap();
+    assert_eq!(message.id, id);
+    assert_eq!(message.headers, headers);
+    let age = SystemTime::now().duration_since(message.published).unwrap();
+    assert!(age < Duration::from_secs(10));
+    assert_eq!(
+        message
+            .expires
+            .unwrap()
+            .duration_since(message.published)
+            .unwrap(),
+        Duration::from_secs(60)
+    );
+    client.ack("orders", message.id).unwrap();
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_none());
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn moves_messages_delivered_too_often_to_dead_letters() {
+    let journal = TempJournal::new("dead-letters");
+    let config = || Config {
+        max_deliveries: 2,
+        ..journal.config()
+    };
+    let server = start_with(config());
+    let mut client = client(&server);
+    let id = client.publish("orders", b"poison").unwrap();
+    let message = client.retrieve("orders", Wait::No).unwrap().unwrap();
+    client.nack("orders", message.id).unwrap();
+    server.shut_down().unwrap();
+
+    // deliveries count towards the limit across restarts
+    let server = start_with(config());
+    let mut client = self::client(&server);
+    let message = client.retrieve("orders", Wait::No).unwrap().unwrap();
+    assert_eq!(message.deliveries, 2);
+    client.nack("orders", message.id).unwrap();
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_none());
+    server.shut_down().unwrap();
+
+    // the dead letter keeps its ID, and isn't moved any further
+    let server = start_with(config());
+    let mut client = self::client(&server);
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_none());
+    for deliveries in 3..5 {
+        let message = client.retrieve("orders.dead", Wait::No).unwrap().unwrap();
+        assert_eq!((message.id, message.deliveries), (id, deliveries));
+        client.nack("orders.dead", message.id).unwrap();
+    }
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn keeps_ids_increasing_across_compaction_and_restart() {
+    let journal = TempJournal::new("compaction");
+    let server = start_with(journal.config());
+    let mut client = client(&server);
+    // enough records for the journal to be compacted once all messages are
+    // acknowledged, leaving none of them in it
+    let batch = vec![(b"x".to_vec(), PublishOptions::default()); 1000];
+    let ids = client.publish_batch("jobs", batch).unwrap();
+    let all = Batch {
+        max: 1000,
+        bytes: None,
+    };
+    for message in client.retrieve_batch("jobs", Wait::No, all).unwrap() {
+        client.ack("jobs", message.id).unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while fs::metadata(&journal.path).unwrap().len() > 100 {
+        assert!(Instant::now() < deadline, "journal wasn't compacted");
+        thread::sleep(Duration::from_millis(50));
+    }
+    server.shut_down().unwrap();
+
+    let server = start_with(journal.config());
+    let id = self::client(&server).publish("jobs", b"y").unwrap();
+    assert!(id > *ids.last().unwrap());
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn delivers_by_priority_then_delay() {
+    let server = start();
+    let mut client = client(&server);
+    let publish = |client: &mut Client, body: &str, priority, delay: Option<Duration>| {
+        let options = PublishOptions {
+            priority,
+            delay,
+            ..PublishOptions::default()
+        };
+        client
+            .publish_with("jobs", body.as_bytes(), options)
+            .unwrap()
+    };
+    publish(&mut client, "low", 0, None);
+    publish(&mut client, "later", 9, Some(Duration::from_millis(300)));
+    publish(&mut client, "high 1", 5, None);
+    publish(&mut client, "high 2", 5, None);
+
+    // highest priority first, in the order published within a priority,
+    // and delayed messages only once due
+    let mut retrieve = |wait| {
+        let message = client.retrieve("jobs", wait).unwrap()?;
+        client.ack("jobs", message.id).unwrap();
+        Some(String::from_utf8(message.body).unwrap())
+    };
+    assert_eq!(retrieve(Wait::No).as_deref(), Some("high 1"));
+    assert_eq!(retrieve(Wait::No).as_deref(), Some("high 2"));
+    assert_eq!(retrieve(Wait::No).as_deref(), Some("low"));
+    assert_eq!(retrieve(Wait::No), None);
+    // a waiting consumer gets a delayed message as soon as it's due
+    let started = Instant::now();
+    let wait = Wait::For(Duration::from_secs(5));
+    assert_eq!(retrieve(wait).as_deref(), Some("later"));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn reports_metrics() {
+    let server = start_with(Config {
+        metrics_listen: Some(String::from("127.0.0.1:0")),
+        ..Config::default()
+    });
+    let mut client = client(&server);
+    client.publish("orders", b"one").unwrap();
+    client.publish("orders", b"two").unwrap();
+    let message = client.retrieve("orders", Wait::No).unwrap().unwrap();
+    client.ack("orders", message.id).unwrap();
+
+    let stats = client.stats().unwrap();
+    let stat = |name: &str| {
+        stats
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| *value)
+    };
+    assert_eq!(stat("messages_published_total"), Some(2.0));
+    assert_eq!(stat("messages_acknowledged_total"), Some(1.0));
+    assert_eq!(stat("mailbox_messages{mailbox=\"orders\"}"), Some(1.0));
+    assert_eq!(stat("connections_active"), Some(1.0));
+    assert_eq!(
+        stat("request_duration_seconds_count{verb=\"PUB\"}"),
+        Some(2.0)
+    );
+
+    let http = |server: &Server, request: &str| {
+        let mut stream = TcpStream::connect(server.metrics_addr().unwrap()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let http_get = |path: &str| {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        http(&server, &request)
+    };
+    let response = http_get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("# TYPE messages_published_total counter\n"));
+    assert!(response.contains("\nmessages_published_total 2\n"));
+    assert!(response.contains("\nmailbox_in_flight{mailbox=\"orders\"} 0\n"));
+    assert!(http_get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    // an oversized request isn't read to its end, the connection is closed
+    // instead, so the response may be lost to a reset
+    let mut stream = TcpStream::connect(server.metrics_addr().unwrap()).unwrap();
+    let request = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(10000));
+    let _ = stream.write_all(request.as_bytes());
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(response.is_empty() || response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    // an idle client doesn't keep others from scraping
+    let _idle = TcpStream::connect(server.metrics_addr().unwrap()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    assert!(http_get("/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(started.elapsed() < Duration::from_secs(1));
+    server.shut_down().unwrap();
+
+    // with a token, metrics are only served to those who have it
+    let server = start_with(Config {
+        metrics_listen: Some(String::from("127.0.0.1:0")),
+        metrics_token: Some(Secret::from(String::from("scrape"))),
+        ..Config::default()
+    });
+    let request = "GET /metrics HTTP/1.1\r\nAuthorization: Bearer guess\r\n\r\n";
+    assert!(http(&server, request).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    let request = "GET /metrics HTTP/1.1\r\nauthorization: Bearer scrape\r\n\r\n";
+    assert!(http(&server, request).starts_with("HTTP/1.1 200 OK\r\n"));
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn publishes_and_retrieves_in_batches() {
+    let journal = TempJournal::new("batches");
+    let config = || Config {
+        max_queue_depth: Some(5),
+        ..journal.config()
+    };
+    let server = start_with(config());
+    let mut client = client(&server);
+    let batch = |bodies: &[&str]| {
+        bodies
+            .iter()
+            .map(|body| (body.as_bytes().to_vec(), PublishOptions::default()))
+            .collect()
+    };
+    let ids = client
+        .publish_batch("jobs", batch(&["one", "two", "three", "four"]))
+        .unwrap();
+    assert_eq!(ids.len(), 4);
+    assert!(ids.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    // none of a batch is published if it doesn't fit
+    match client.publish_batch("jobs", batch(&["five", "six"])) {
+        Err(ClientError::Server { code: 507, .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // the batch stops before the message which would go over the byte
+    // budget, but the first message is taken whatever its size
+    let bodies = |messages: &[Message]| {
+        messages
+            .iter()
+            .map(|m| String::from_utf8(m.body.clone()).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let limit = |max, bytes| Batch { max, bytes };
+    let taken = client
+        .retrieve_batch("jobs", Wait::No, limit(3, Some(6)))
+        .unwrap();
+    assert_eq!(bodies(&taken), ["one", "two"]);
+    for message in &taken {
+        client.ack("jobs", message.id).unwrap();
+    }
+    let taken = client
+        .retrieve_batch("jobs", Wait::No, limit(10, Some(1)))
+        .unwrap();
+    assert_eq!(bodies(&taken), ["three"]);
+    server.shut_down().unwrap();
+
+    // batches are replayed from the journal like single messages
+    let server = start_with(config());
+    let mut client = self::client(&server);
+    let taken = client
+        .retrieve_batch("jobs", Wait::No, limit(10, None))
+        .unwrap();
+    assert_eq!(bodies(&taken), ["three", "four"]);
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn administers_mailboxes() {
+    let journal = TempJournal::new("admin");
+    let config = || journal.config();
+    let server = start_with(config());
+    let mut client = client(&server);
+    for body in ["one", "two", "three", "four"] {
+        client.publish("orders", body.as_bytes()).unwrap();
+    }
+    client.publish("archive", b"keep").unwrap();
+
+    // peeking neither delivers nor changes messages
+    let peeked = client.peek("orders", 2).unwrap();
+    assert_eq!(peeked.len(), 2);
+    assert_eq!(peeked[0].body, b"one");
+    assert_eq!(peeked[0].deliveries, 0);
+    assert_eq!(client.count("orders").unwrap(), (4, 15));
+
+    let taken = client.retrieve("orders", Wait::No).unwrap().unwrap();
+    assert_eq!(taken.body, b"one");
+    assert_eq!(client.count("orders").unwrap(), (3, 12));
+    // messages can be deleted whether in flight or waiting
+    client.delete("orders", taken.id).unwrap();
+    client.delete("orders", peeked[1].id).unwrap();
+    match client.delete("orders", peeked[1].id) {
+        Err(ClientError::Server { code: 404, .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match client.ack("orders", taken.id) {
+        Err(ClientError::Server { code: 404, .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(client.count("orders").unwrap(), (2, 9));
+    server.shut_down().unwrap();
+
+    // deletions are journaled, and purging leaves other mailboxes alone
+    let server = start_with(config());
+    let mut client = self::client(&server);
+    let bodies: Vec<_> = client
+        .peek("orders", 10)
+        .unwrap()
+        .into_iter()
+        .map(|m| m.body)
+        .collect();
+    assert_eq!(bodies, [b"three".to_vec(), b"four".to_vec()]);
+    client.retrieve("orders", Wait::No).unwrap().unwrap();
+    assert_eq!(client.purge("orders").unwrap(), 2);
+    assert_eq!(client.count("orders").unwrap(), (0, 0));
+    assert_eq!(client.count("archive").unwrap(), (1, 4));
+    server.shut_down().unwrap();
+
+    let server = start_with(config());
+    let mut client = self::client(&server);
+    assert!(client.retrieve("orders", Wait::No).unwrap().is_none());
+    assert_eq!(client.count("archive").unwrap(), (1, 4));
+    server.shut_down().unwrap();
+}
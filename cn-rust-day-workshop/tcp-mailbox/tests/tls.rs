@@ -0,0 +1,303 @@
+//! End-to-end tests of TLS connections, with certificates generated for
+//! each test.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use tcp_mailbox::client::{self, Client, ClientOptions};
+use tcp_mailbox::config::Config;
+use tcp_mailbox::error::Error;
+use tcp_mailbox::protocol::{self, Reply, Request, Wait};
+use tcp_mailbox::server::Server;
+use tcp_mailbox::tls::{self, TlsStream};
+
+/// Directory for the certificates of a single test, removed when dropped
+struct Certs {
+    dir: PathBuf,
+}
+
+impl Certs {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "tcp-mailbox-tls-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        Certs { dir }
+    }
+
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Generate a CA, returns it along with the path of its certificate
+    fn ca(&self, name: &str) -> (Certificate, KeyPair, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        let path = self.write(&format!("{}.pem", name), &cert.pem());
+        (cert, key, path)
+    }
+
+    /// Generate a certificate signed by a CA, returns paths of the
+    /// certificate and its key
+    fn signed(
+        &self,
+        name: &str,
+        usage: ExtendedKeyUsagePurpose,
+        (ca, ca_key): (&Certificate, &KeyPair),
+    ) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let names = vec![String::from("localhost"), String::from("127.0.0.1")];
+        let mut params = CertificateParams::new(names).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        (
+            self.write(&format!("{}.pem", name), &cert.pem()),
+            self.write(&format!("{}.key", name), &key.serialize_pem()),
+        )
+    }
+}
+
+impl Drop for Certs {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn start(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Server {
+    let config = Config {
+        listen: vec![String::from("127.0.0.1:0")],
+        tls_cert: Some(cert.to_owned()),
+        tls_key: Some(key.to_owned()),
+        tls_client_ca: client_ca.map(Path::to_owned),
+        ..Config::default()
+    };
+    Server::start(config).expect("failed to start server")
+}
+
+fn connect(server: &Server, tls: Option<Arc<ClientConfig>>) -> client::Result<Client> {
+    let options = ClientOptions {
+        tls,
+        // a client and server that don't speak the same protocol may both
+        // wait for the other to speak first
+        timeout: Some(Duration::from_secs(1)),
+        retries: 0,
+        ..ClientOptions::default()
+    };
+    Client::with_options(&server.local_addr().to_string(), options)
+}
+
+#[test]
+fn exchanges_messages_over_tls() {
+    let certs = Certs::new();
+    let (ca, ca_key, ca_path) = certs.ca("ca");
+    let (cert, key) = certs.signed(
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+        (&ca, &ca_key),
+    );
+    let server = start(&cert, &key, None);
+
+    let tls = tls::client_config(&ca_path, None).unwrap();
+    let mut client = connect(&server, Some(tls)).unwrap();
+    client.publish("secrets", b"hush").unwrap();
+    let message = client.retrieve("secrets", Wait::No).unwrap().unwrap();
+    assert_eq!(message.body, b"hush");
+    client.ack("secrets", message.id).unwrap();
+
+    // the server only speaks TLS
+    assert!(connect(&server, None).is_err());
+    server.shut_down().unwrap();
+}
+
+/// Connect over TLS without the client, to send requests it doesn't support
+fn connect_raw(server: &Server, tls: &Arc<ClientConfig>) -> TlsStream {
+    let tcp = TcpStream::connect(server.local_addr()).unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    TlsStream::connect(tcp, Arc::clone(tls), name).unwrap()
+}
+
+fn send(mut stream: &TlsStream, request: Request) {
+    protocol::write_request(&mut stream, &request).unwrap();
+    stream.flush().unwrap();
+}
+
+fn receive(reader: &mut BufReader<&TlsStream>) -> Reply {
+    protocol::read_reply(reader, 1024).unwrap().unwrap()
+}
+
+#[test]
+fn serves_subscriptions_over_tls() {
+    let certs = Certs::new();
+    let (ca, ca_key, ca_path) = certs.ca("ca");
+    let (cert, key) = certs.signed(
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+        (&ca, &ca_key),
+    );
+    let server = start(&cert, &key, None);
+    let tls = tls::client_config(&ca_path, None).unwrap();
+
+    // the server reads and writes at the same time while subscribed
+    let subscriber = connect_raw(&server, &tls);
+    let mut subscriber_reader = BufReader::new(&subscriber);
+    assert!(matches!(receive(&mut subscriber_reader), Reply::Ok(_)));
+    send(
+        &subscriber,
+        Request::Subscribe {
+            topic: String::from("news"),
+            buffer: None,
+            overflow: None,
+        },
+    );
+
+    let publisher = connect_raw(&server, &tls);
+    let mut publisher_reader = BufReader::new(&publisher);
+    assert!(matches!(receive(&mut publisher_reader), Reply::Ok(_)));
+    // the subscription starts at some point after subscribing
+    loop {
+        let cast = Request::Cast {
+            topic: String::from("news"),
+            message: b"extra".to_vec(),
+        };
+        send(&publisher, cast);
+        if receive(&mut publisher_reader) == Reply::Ok(String::from("1")) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    match receive(&mut subscriber_reader) {
+        Reply::Message { body, .. } => assert_eq!(body, b"extra"),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    send(&subscriber, Request::Unsubscribe);
+    assert_eq!(receive(&mut subscriber_reader), Reply::Ok(String::new()));
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn detects_truncated_sessions() {
+    let certs = Certs::new();
+    let (ca, ca_key, ca_path) = certs.ca("ca");
+    let (cert, key) = certs.signed(
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+        (&ca, &ca_key),
+    );
+    let server_tls = tls::server_config(&cert, &key, None).unwrap();
+    let client_tls = tls::client_config(&ca_path, None).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let peer = thread::spawn(move || {
+        for truncate in [false, true].iter() {
+            let (tcp, _) = listener.accept().unwrap();
+            let stream = TlsStream::accept(tcp, Arc::clone(&server_tls)).unwrap();
+            (&stream).write_all(b"hello").unwrap();
+            (&stream).flush().unwrap();
+            if *truncate {
+                // dropping the stream ends the session, unless the
+                // connection is already closed
+                stream.tcp().shutdown(Shutdown::Both).unwrap();
+            }
+        }
+    });
+
+    let read_all = || {
+        let tcp = TcpStream::connect(addr).unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = TlsStream::connect(tcp, Arc::clone(&client_tls), name).unwrap();
+        let mut data = Vec::new();
+        let result = (&stream).read_to_end(&mut data);
+        assert_eq!(data, b"hello");
+        result
+    };
+    assert_eq!(read_all().unwrap(), 5);
+    let e = read_all().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    peer.join().unwrap();
+}
+
+#[test]
+fn client_verifies_server_certificate() {
+    let certs = Certs::new();
+    let (ca, ca_key, _) = certs.ca("ca");
+    let (_, _, other_ca_path) = certs.ca("other-ca");
+    let (cert, key) = certs.signed(
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+        (&ca, &ca_key),
+    );
+    let server = start(&cert, &key, None);
+
+    let tls = tls::client_config(&other_ca_path, None).unwrap();
+    assert!(connect(&server, Some(tls)).is_err());
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn mutual_tls_requires_client_certificate() {
+    let certs = Certs::new();
+    let (ca, ca_key, ca_path) = certs.ca("ca");
+    let (other_ca, other_ca_key, _) = certs.ca("other-ca");
+    let (cert, key) = certs.signed(
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+        (&ca, &ca_key),
+    );
+    let server = start(&cert, &key, Some(&ca_path));
+
+    let ping = |identity: Option<(&Path, &Path)>| {
+        let tls = tls::client_config(&ca_path, identity).unwrap();
+        connect(&server, Some(tls)).and_then(|mut client| client.ping())
+    };
+    let (trusted_cert, trusted_key) = certs.signed(
+        "trusted",
+        ExtendedKeyUsagePurpose::ClientAuth,
+        (&ca, &ca_key),
+    );
+    let (untrusted_cert, untrusted_key) = certs.signed(
+        "untrusted",
+        ExtendedKeyUsagePurpose::ClientAuth,
+        (&other_ca, &other_ca_key),
+    );
+    assert!(ping(Some((&trusted_cert, &trusted_key))).is_ok());
+    assert!(ping(Some((&untrusted_cert, &untrusted_key))).is_err());
+    assert!(ping(None).is_err());
+    server.shut_down().unwrap();
+}
+
+#[test]
+fn refuses_to_start_with_incomplete_tls_config() {
+    let certs = Certs::new();
+    let (ca, ca_key, _) = certs.ca("ca");
+    let (cert, _) = certs.signed(
+        "server",
+        ExtendedKeyUsagePurpose::ServerAuth,
+        (&ca, &ca_key),
+    );
+    let config = Config {
+        listen: vec![String::from("127.0.0.1:0")],
+        tls_cert: Some(cert),
+        ..Config::default()
+    };
+    assert!(matches!(Server::start(config), Err(Error::Tls(_))));
+}
@@ -0,0 +1,112 @@
+//! Estimating monthly cost of resource requests from a price table
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::utilisation::InstallationCounters;
+
+/// Monthly prices of resources
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Prices {
+    /// price of 1GiB of memory for a month
+    pub gib_month: f64,
+    /// price of 1 vCPU for a month
+    pub vcpu_month: f64,
+}
+
+/// Prices for an installation, either given directly or by naming the region
+/// it runs in; unknown fields are denied so an entry mixing both is rejected
+/// rather than silently matching one variant and dropping the other key
+#[derive(Debug, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum Override {
+    Prices { gib_month: f64, vcpu_month: f64 },
+    Region { region: String },
+}
+
+/// Default prices with optional overrides per region in `[regions.NAME]`
+/// tables and per installation in `[installations.NAME]` tables; an
+/// installation override either has prices of its own or a `region` key
+/// naming the region whose prices apply to it
+#[derive(Debug, Deserialize)]
+pub struct PriceTable {
+    #[serde(flatten)]
+    default: Prices,
+    #[serde(default)]
+    regions: HashMap<String, Prices>,
+    #[serde(default)]
+    installations: HashMap<String, Override>,
+}
+
+/// Estimated monthly cost of a row, split by resource
+#[derive(Debug, Clone, Copy)]
+pub struct Cost {
+    pub mem: f64,
+    pub cpu: f64,
+}
+
+impl Cost {
+    pub fn total(&self) -> f64 {
+        self.mem + self.cpu
+    }
+
+    pub fn add(&mut self, other: &Cost) {
+        self.mem += other.mem;
+        self.cpu += other.cpu;
+    }
+}
+
+impl PriceTable {
+    /// Load price table from a TOML file
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| {
+            format!("Could not read price table {:?}", path)
+        })?;
+        let table: PriceTable = toml::from_str(&contents)
+            .with_context(|| format!("Invalid price table {:?}", path))?;
+        for (installation, o) in &table.installations {
+            match o {
+                Override::Region { region }
+                    if !table.regions.contains_key(region) =>
+                {
+                    return Err(anyhow!(
+                        "Installation {:?} refers to unknown region {:?} in \
+                         price table {:?}",
+                        installation,
+                        region,
+                        path
+                    ))
+                }
+                _ => (),
+            }
+        }
+        Ok(table)
+    }
+
+    /// Prices applicable to an installation
+    fn prices_for(&self, installation: &str) -> Prices {
+        match self.installations.get(installation) {
+            Some(Override::Prices {
+                gib_month,
+                vcpu_month,
+            }) => Prices {
+                gib_month: *gib_month,
+                vcpu_month: *vcpu_month,
+            },
+            Some(Override::Region { region }) => self.regions[region],
+            None => self.default,
+        }
+    }
+
+    /// Cost of requests in a row, since that's what is paid for regardless
+    /// of actual utilisation
+    pub fn cost_of(&self, row: &InstallationCounters) -> Cost {
+        let prices = self.prices_for(&row.name);
+        Cost {
+            mem: row.mem_requests_total as f64 / 1024.0 * prices.gib_month,
+            cpu: row.cpu_requests_total as f64 / 1000.0 * prices.vcpu_month,
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Ways of grouping pods into rows of the summary
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+
+/// A namespace pattern and the name of the group matching pods belong to
+#[derive(Debug)]
+pub struct NamespaceRule {
+    group: String,
+    pattern: Regex,
+    /// give each matching pod a row of its own instead of summing them up
+    per_pod: bool,
+    /// output a row for the group even if no pods in a section match
+    always: bool,
+}
+
+impl NamespaceRule {
+    /// Parse a rule from a `GROUP=REGEX` string
+    pub fn parse(s: &str) -> Result<Self> {
+        let (group, pattern) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected GROUP=REGEX, got {:?}", s))?;
+        Ok(NamespaceRule {
+            group: group.to_owned(),
+            pattern: Regex::new(pattern).with_context(|| {
+                format!("Invalid namespace pattern {:?}", s)
+            })?,
+            per_pod: false,
+            always: false,
+        })
+    }
+}
+
+/// Determines which group, if any, a pod is counted towards within a section
+#[derive(Debug)]
+pub enum Grouping {
+    /// all pods in a section are counted in a single group
+    Section,
+    /// pods are grouped by the first rule matching their namespace, pods in
+    /// namespaces not matching any rule are skipped
+    Namespace(Vec<NamespaceRule>),
+    /// pods are grouped by the first capture group of a regex matched against
+    /// their name, pods not matching it are skipped
+    PodName(Regex),
+}
+
+impl Grouping {
+    /// Monolithic Prometheus in `monitoring` namespace (old) vs shards in
+    /// `*-prometheus` namespaces (new); each old pod gets a row of its own and
+    /// there's always a new row, even for installations without any shards yet
+    pub fn prometheus() -> Self {
+        Grouping::Namespace(vec![
+            NamespaceRule {
+                per_pod: true,
+                ..NamespaceRule::parse("old=^monitoring$").unwrap()
+            },
+            NamespaceRule {
+                always: true,
+                ..NamespaceRule::parse("new=-prometheus$").unwrap()
+            },
+        ])
+    }
+
+    /// Construct grouping by a regex with a capture group for the group name
+    pub fn pod_name(pattern: &str) -> Result<Self> {
+        let re = Regex::new(pattern)
+            .with_context(|| format!("Invalid pod name regex {:?}", pattern))?;
+        if re.captures_len() < 2 {
+            return Err(anyhow!(
+                "Pod name regex {:?} has no capture group",
+                pattern
+            ));
+        }
+        Ok(Grouping::PodName(re))
+    }
+
+    /// Name of the group a pod belongs to, if it should be counted at all
+    pub fn group_for(&self, namespace: &str, pod: &str) -> Option<String> {
+        match self {
+            Grouping::Section => Some(String::new()),
+            Grouping::Namespace(rules) => rules
+                .iter()
+                .find(|r| r.pattern.is_match(namespace))
+                .map(|r| r.group.clone()),
+            Grouping::PodName(re) => re
+                .captures(pod)
+                .and_then(|c| c.get(1))
+                .map(|m| m.as_str().to_owned()),
+        }
+    }
+
+    /// Whether pods in a group get a row each rather than being summed up
+    pub fn per_pod(&self, group: &str) -> bool {
+        match self {
+            Grouping::Namespace(rules) => {
+                rules.iter().any(|r| r.group == group && r.per_pod)
+            }
+            _ => false,
+        }
+    }
+
+    /// Groups that get a row in every section, even if no pods match them
+    pub fn fixed_groups(&self) -> Vec<&str> {
+        match self {
+            Grouping::Namespace(rules) => rules
+                .iter()
+                .filter(|r| r.always)
+                .map(|r| r.group.as_str())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Sort key for rows within a section; namespace groups come in the order
+    /// of their rules, other groups in the order they were first seen
+    pub fn rank(&self, group: &str) -> usize {
+        match self {
+            Grouping::Namespace(rules) => rules
+                .iter()
+                .position(|r| r.group == group)
+                .unwrap_or(rules.len()),
+            _ => 0,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Opening of input files, transparently decompressing them if needed
+
+use anyhow::{Context, Result};
+use flate2::bufread::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Number of bytes needed to recognise any of the magic numbers above
+const MAGIC_LEN: u64 = 4;
+
+/// Compression format of an input stream
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Guess compression format from file name extension
+    fn from_extension(filename: &str) -> Option<Self> {
+        match Path::new(filename).extension()?.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Detect compression format from the first few bytes of a stream, None
+    /// if there are too few bytes to tell
+    fn from_magic(buf: &[u8]) -> Option<Self> {
+        if buf.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if buf.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if GZIP_MAGIC.starts_with(buf) || ZSTD_MAGIC.starts_with(buf) {
+            None
+        } else {
+            Some(Compression::None)
+        }
+    }
+}
+
+/// Open `filename` for reading, or stdin if it's `-`
+///
+/// Gzip and zstd compressed input is decompressed on the fly; the format is
+/// detected from magic bytes, so mislabelled files are read correctly, and
+/// only taken from the file extension if the input is too short to tell.
+pub fn read_input(filename: &str) -> Result<Box<dyn BufRead>> {
+    if filename == "-" {
+        return decompress(io::stdin().lock(), None);
+    }
+
+    let file = File::open(filename)
+        .with_context(|| format!("Could not open file {:?}", &filename))?;
+    decompress(BufReader::new(file), Compression::from_extension(filename))
+        .with_context(|| format!("Could not read file {:?}", &filename))
+}
+
+fn decompress<R: BufRead + 'static>(
+    mut reader: R,
+    fallback: Option<Compression>,
+) -> Result<Box<dyn BufRead>> {
+    // A single read from a pipe may return fewer bytes than the magic number,
+    // so keep reading until there are enough to tell or the input ends, and
+    // put them back in front of the rest of the stream afterwards
+    let mut head = Vec::with_capacity(MAGIC_LEN as usize);
+    reader.by_ref().take(MAGIC_LEN).read_to_end(&mut head)?;
+    let compression = Compression::from_magic(&head)
+        .or(fallback)
+        .unwrap_or(Compression::None);
+    let reader = io::Cursor::new(head).chain(reader);
+
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => {
+            Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+        }
+        Compression::Zstd => Box::new(BufReader::new(
+            zstd::Decoder::with_buffer(reader)
+                .context("Could not initialise zstd decoder")?,
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    /// Reader returning at most one byte per read, like a slow pipe
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn read_all(input: Vec<u8>) -> Result<String> {
+        let reader =
+            BufReader::with_capacity(1, Trickle(io::Cursor::new(input)));
+        let mut output = String::new();
+        decompress(reader, None)?.read_to_string(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn detects_compression_from_short_reads() -> Result<()> {
+        let text = "section a\nline 1\n";
+
+        let mut gzip =
+            GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(text.as_bytes())?;
+        assert_eq!(read_all(gzip.finish()?)?, text);
+
+        assert_eq!(read_all(zstd::encode_all(text.as_bytes(), 0)?)?, text);
+        assert_eq!(read_all(text.as_bytes().to_vec())?, text);
+        assert_eq!(read_all(vec![0x1f])?, "\u{1f}");
+        Ok(())
+    }
+}
@@ -1,205 +1,312 @@
 use anyhow::{anyhow, Context, Result};
-use core::iter::Peekable;
-use std::env::{self, Args};
-use std::fs::File;
-use std::io::Write;
-use std::io::{self, Read};
-use std::mem;
+use std::io::{self, BufRead, Write};
 use std::process;
-use std::str::Lines;
+use std::str::FromStr;
+use structopt::StructOpt;
 use tabwriter::TabWriter;
 
-fn main() {
-    let filename = parse_args(&mut env::args());
-    let contents = read_input(&filename).unwrap_or_else(|err| {
-        eprintln!("Could not read input: {}", err);
-        process::exit(1);
-    });
+mod cost;
+mod grouping;
+mod input;
+mod utilisation;
 
-    print!(
-        "{}",
-        summarize(&contents).unwrap_or_else(|err| {
-            eprintln!("Failed processing contents: {}", err);
-            process::exit(1);
-        })
-    );
-}
-
-fn parse_args(args: &mut Args) -> String {
-    let mut filename = String::from("-");
-    if let Some(f) = args.nth(1) {
-        filename = f;
-    };
-    filename
-}
+use cost::{Cost, PriceTable};
+use grouping::{Grouping, NamespaceRule};
+use input::read_input;
+use utilisation::{InstallationCounters, ParseOptions, UtilisationData};
 
-fn read_input(filename: &str) -> Result<String> {
-    let stdin; // for holding a reference throughout the scope
-    let mut input: Box<dyn Read> =
-        if filename == "-" {
-            stdin = io::stdin();
-            Box::new(stdin.lock())
-        } else {
-            Box::new(File::open(filename).with_context(|| {
-                format!("Could not open file {:?}", &filename)
-            })?)
-        };
-
-    let mut s = String::new();
-    input.read_to_string(&mut s)?;
-    Ok(s)
+/// Ways of grouping pods selectable with `--group-by`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum GroupBy {
+    Prometheus,
+    Section,
+    Namespace,
+    Pod,
 }
 
-/// Parse kubectl-style memory size string in MiB
-/// Future improvements: support for other units, proper error handling.
-fn parse_mem(s: &str) -> u64 {
-    s.trim_end_matches("Mi").parse().unwrap_or_default()
+impl GroupBy {
+    const VARIANTS: &'static [&'static str] =
+        &["prometheus", "section", "namespace", "pod"];
 }
 
-/// Per-installation counters and resource usage totals
-#[derive(Debug)]
-struct InstallationCounters {
-    /// name of this installation
-    name: String,
-    /// tag for this set of counters, e.g. old/new, used to designate which
-    /// instance of Prometheus the data is from
-    tag: String,
-    /// number of shards in this installation
-    shard_count: u32,
-    /// sum of memory requests from all shards
-    mem_requests_total: u64,
-    /// sum of memory limits from all shards
-    mem_limits_total: u64,
-    /// sum of memory utilisation from all shards
-    mem_util_total: u64,
-}
+impl FromStr for GroupBy {
+    type Err = anyhow::Error;
 
-impl InstallationCounters {
-    fn new() -> Self {
-        InstallationCounters {
-            name: String::new(),
-            tag: String::from("new"),
-            shard_count: 0,
-            mem_requests_total: 0,
-            mem_limits_total: 0,
-            mem_util_total: 0,
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "prometheus" => Ok(GroupBy::Prometheus),
+            "section" => Ok(GroupBy::Section),
+            "namespace" => Ok(GroupBy::Namespace),
+            "pod" => Ok(GroupBy::Pod),
+            _ => Err(anyhow!("unknown grouping {:?}", s)),
         }
     }
 }
 
-/// Implements iteration over utilisation data
-struct UtilisationData<'a> {
-    data: Peekable<Lines<'a>>,
-    current: InstallationCounters,
+/// Summarise memory usage of Prometheus (or other) installations from kubectl
+/// resource-capacity output.
+#[derive(Debug, StructOpt)]
+struct Cli {
+    /// The path to the file to read, `-` for stdin; may be gzip or zstd
+    /// compressed
+    #[structopt(default_value = "-")]
+    filename: String,
+
+    /// Write out each row as soon as its section is processed instead of
+    /// aligning the whole table at the end
+    #[structopt(long)]
+    stream: bool,
+
+    /// Output a row per container rather than per pod, for input captured
+    /// with `kubectl resource-capacity --containers`
+    #[structopt(long)]
+    by_container: bool,
+
+    /// Leave a container (e.g. a sidecar) out of the totals, can be given
+    /// multiple times; only has an effect on input with a CONTAINER column
+    #[structopt(long = "exclude-container", number_of_values = 1)]
+    exclude_containers: Vec<String>,
+
+    /// How pods are grouped within a section: `prometheus` compares the old
+    /// monolith with new shards, `section` sums all pods, `namespace` uses
+    /// --namespace-group rules and `pod` uses --pod-regex
+    #[structopt(
+        long,
+        default_value = "prometheus",
+        possible_values = GroupBy::VARIANTS,
+    )]
+    group_by: GroupBy,
+
+    /// Rule for `--group-by namespace` in GROUP=REGEX form, can be given
+    /// multiple times; pods count towards the first rule matching their
+    /// namespace
+    #[structopt(long = "namespace-group", number_of_values = 1)]
+    namespace_groups: Vec<String>,
+
+    /// Regex matched against pod names for `--group-by pod`, its first
+    /// capture group is used as the group name
+    #[structopt(long)]
+    pod_regex: Option<String>,
+
+    /// Header of the group column, PROM for `--group-by prometheus` and GROUP
+    /// otherwise by default
+    #[structopt(long)]
+    group_column: Option<String>,
+
+    /// TOML price table with `gib_month` and `vcpu_month` prices, optionally
+    /// overridden per region in a `[regions.NAME]` table and per installation
+    /// in an `[installations.NAME]` table with prices or a `region`; adds
+    /// monthly cost of requests and fleet totals to the output
+    #[structopt(long)]
+    prices: Option<String>,
 }
 
-impl<'a> UtilisationData<'a> {
-    /// Construct UtilisationData from a str
-    fn from(s: &'a str) -> Self {
-        UtilisationData {
-            data: s.lines().peekable(),
-            current: InstallationCounters::new(),
+impl Cli {
+    /// Construct the grouping selected by `--group-by` and its options,
+    /// rejecting options that would have no effect with it
+    fn grouping(&self) -> Result<Grouping> {
+        if self.group_by != GroupBy::Namespace
+            && !self.namespace_groups.is_empty()
+        {
+            return Err(anyhow!(
+                "--namespace-group can only be used with --group-by namespace"
+            ));
+        }
+        if self.group_by != GroupBy::Pod && self.pod_regex.is_some() {
+            return Err(anyhow!(
+                "--pod-regex can only be used with --group-by pod"
+            ));
+        }
+        if self.group_by == GroupBy::Section && self.group_column.is_some() {
+            return Err(anyhow!(
+                "--group-column can't be used with --group-by section, which \
+                 has no group column"
+            ));
         }
-    }
-}
 
-impl<'a> Iterator for UtilisationData<'a> {
-    type Item = InstallationCounters;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.data.peek() {
-            // first line in the section should contain the installation name
-            // but we could be re-entering `next()` after returning a row for
-            // an old installation, so check that
-            Some(nxt) if nxt.starts_with("# ") => {
-                if !self.current.name.is_empty() {
-                    // edge case, last iteration returned early (e.g. old
-                    // installation row) but it was also the last line in
-                    // current section; flush it and start fresh
-                    return Some(mem::replace(
-                        &mut self.current,
-                        InstallationCounters::new(),
-                    ));
-                }
-                let line = self.data.next().unwrap();
-                self.current.name.push_str(line.trim_start_matches("# "));
+        match self.group_by {
+            GroupBy::Prometheus => Ok(Grouping::prometheus()),
+            GroupBy::Section => Ok(Grouping::Section),
+            GroupBy::Namespace if self.namespace_groups.is_empty() => {
+                Err(anyhow!(
+                    "--group-by namespace requires at least one \
+                     --namespace-group"
+                ))
+            }
+            GroupBy::Namespace => Ok(Grouping::Namespace(
+                self.namespace_groups
+                    .iter()
+                    .map(|r| NamespaceRule::parse(r))
+                    .collect::<Result<_>>()?,
+            )),
+            GroupBy::Pod => {
+                let pattern = self.pod_regex.as_deref().ok_or_else(|| {
+                    anyhow!("--group-by pod requires --pod-regex")
+                })?;
+                Grouping::pod_name(pattern)
             }
-            // non header line, will be processed below
-            Some(_) => (),
-            // end of input
-            None => return None,
         }
+    }
+}
 
-        while let Some(line) = self.data.next() {
-            if line.trim().is_empty() {
-                continue;
-            }
+/// Options controlling the layout of the output table
+#[derive(Debug)]
+struct OutputOptions {
+    /// header of the group column, None to leave the column out
+    group_column: Option<String>,
+    /// header of the pod count column
+    count_column: &'static str,
+    /// output a container column
+    by_container: bool,
+    /// write out each row as soon as it's available
+    stream: bool,
+    /// prices for cost columns, None to leave them out
+    prices: Option<PriceTable>,
+}
 
-            let s: Vec<_> = line.split_whitespace().collect();
-            if s.len() < 15 {
-                // should not happen but if it does it's not a useful line
-                continue;
-            }
+fn main() {
+    let args = Cli::from_args();
+    let grouping = args.grouping().unwrap_or_else(|err| {
+        eprintln!("Invalid arguments: {}", err);
+        process::exit(2);
+    });
+    let prices = args.prices.as_deref().map(PriceTable::from_file);
+    let prices = prices.transpose().unwrap_or_else(|err| {
+        eprintln!("Could not load prices: {:#}", err);
+        process::exit(1);
+    });
+    // only open the input once the arguments are known to be valid, reading
+    // stdin would otherwise wait for data before reporting them
+    let input = read_input(&args.filename).unwrap_or_else(|err| {
+        eprintln!("Could not read input: {}", err);
+        process::exit(1);
+    });
+    let prometheus = args.group_by == GroupBy::Prometheus;
+    let layout = OutputOptions {
+        group_column: match grouping {
+            Grouping::Section => None,
+            _ => Some(args.group_column.unwrap_or_else(|| {
+                String::from(if prometheus { "PROM" } else { "GROUP" })
+            })),
+        },
+        count_column: if prometheus { "SHARDS" } else { "PODS" },
+        by_container: args.by_container,
+        stream: args.stream,
+        prices,
+    };
+    let options = ParseOptions {
+        grouping,
+        by_container: args.by_container,
+        exclude_containers: args.exclude_containers,
+    };
 
-            let namespace = s[1];
-
-            if namespace == "monitoring" {
-                // add data from current line into a new instance of
-                // InstallationCounters and return it; we'll continue with this
-                // installation's shards on next call to `next()`
-                return Some(InstallationCounters {
-                    name: self.current.name.clone(),
-                    tag: "old".to_owned(),
-                    shard_count: 1,
-                    mem_requests_total: parse_mem(s[9]),
-                    mem_limits_total: parse_mem(s[11]),
-                    mem_util_total: parse_mem(s[13]),
-                });
-            }
+    let stdout = io::stdout();
+    if let Err(err) = summarize(input, stdout.lock(), options, layout) {
+        eprintln!("Failed processing contents: {}", err);
+        process::exit(1);
+    }
+}
 
-            if namespace.ends_with("-prometheus") {
-                self.current.shard_count += 1;
-                self.current.mem_requests_total += parse_mem(s[9]);
-                self.current.mem_limits_total += parse_mem(s[11]);
-                self.current.mem_util_total += parse_mem(s[13]);
-            }
+/// Minimum column width used when streaming, since rows are aligned
+/// independently of each other in that mode
+const STREAM_MIN_WIDTH: usize = 12;
+
+fn summarize<R: BufRead, W: Write>(
+    input: R,
+    output: W,
+    options: ParseOptions,
+    layout: OutputOptions,
+) -> Result<()> {
+    let mut tw = if layout.stream {
+        TabWriter::new(output).minwidth(STREAM_MIN_WIDTH)
+    } else {
+        TabWriter::new(output)
+    };
 
-            if let Some(nxt) = self.data.peek() {
-                if nxt.starts_with("# ") {
-                    // we reached the end of current section, break out
-                    break;
+    let header = Some("INSTALLATION")
+        .into_iter()
+        .chain(layout.group_column.as_deref())
+        .chain(layout.by_container.then_some("CONTAINER"))
+        .chain(vec![layout.count_column, "REQUESTS", "LIMITS", "UTIL"])
+        .chain(
+            layout
+                .prices
+                .iter()
+                .flat_map(|_| vec!["MEM_COST", "CPU_COST", "COST"]),
+        )
+        .collect::<Vec<_>>()
+        .join("\t");
+    writeln!(tw, "{}", header)?;
+
+    // fleet totals for each group, only output along with costs; costs are
+    // summed up rather than calculated from totals since prices can differ
+    // between installations
+    let mut totals: Vec<(InstallationCounters, Cost)> = Vec::new();
+    for row in UtilisationData::from(input, options) {
+        let row = row?;
+        let cost = layout.prices.as_ref().map(|p| p.cost_of(&row));
+        if let Some(cost) = cost {
+            match totals.iter_mut().find(|(t, _)| {
+                t.group == row.group && t.container == row.container
+            }) {
+                Some((total, total_cost)) => {
+                    total.add(&row);
+                    total_cost.add(&cost);
+                }
+                None => {
+                    let mut total = row.empty_like("TOTAL");
+                    total.add(&row);
+                    totals.push((total, cost));
                 }
             }
         }
 
-        // end of section or end of input reached, return current counters and
-        // reset to prepare for the next section (if any)
-        Some(mem::replace(&mut self.current, InstallationCounters::new()))
+        writeln!(tw, "{}", format_row(row, cost, &layout)?)?;
+        if layout.stream {
+            tw.flush().context("Writing output failed")?;
+        }
     }
+    for (total, cost) in totals {
+        writeln!(tw, "{}", format_row(total, Some(cost), &layout)?)?;
+    }
+    tw.flush().context("Writing output failed")
 }
 
-fn summarize(contents: &str) -> Result<String> {
-    let mut tw = TabWriter::new(vec![]);
-
-    writeln!(tw, "INSTALLATION\tPROM\tSHARDS\tREQUESTS\tLIMITS\tUTIL")?;
-    for row in UtilisationData::from(contents) {
-        let line = vec![row.name, row.tag, row.shard_count.to_string()]
+/// Format a row of the output table as tab separated cells
+fn format_row(
+    row: InstallationCounters,
+    cost: Option<Cost>,
+    layout: &OutputOptions,
+) -> Result<String> {
+    let group = if layout.group_column.is_some() {
+        Some(row.group)
+    } else {
+        None
+    };
+    let container = if layout.by_container {
+        row.container
+    } else {
+        None
+    };
+    Some(row.name)
+        .into_iter()
+        .chain(group)
+        .chain(container)
+        .chain(Some(row.shard_count.to_string()))
+        .chain(
+            vec![
+                row.mem_requests_total,
+                row.mem_limits_total,
+                row.mem_util_total,
+            ]
             .into_iter()
-            .chain(
-                vec![
-                    row.mem_requests_total,
-                    row.mem_limits_total,
-                    row.mem_util_total,
-                ]
+            .map(|v| format!("{}Mi", v)),
+        )
+        .chain(cost.iter().flat_map(|c| {
+            vec![c.mem, c.cpu, c.total()]
                 .into_iter()
-                .map(|v| format!("{}Mi", v)),
-            )
-            .reduce(|a, b| format!("{}\t{}", a, b))
-            .ok_or(anyhow!("empty iterator"))?;
-
-        writeln!(tw, "{}", line)?;
-    }
-    tw.flush()?;
-
-    String::from_utf8(tw.into_inner()?).context("Formatting output failed")
+                .map(|v| format!("{:.2}", v))
+        }))
+        .reduce(|a, b| format!("{}\t{}", a, b))
+        .ok_or(anyhow!("empty iterator"))
 }
@@ -0,0 +1,257 @@
+//! Parsing of kubectl resource-capacity output into per-installation totals
+
+use anyhow::{Context, Result};
+use core::iter::Peekable;
+use std::collections::VecDeque;
+use std::io::{BufRead, Lines};
+
+use crate::grouping::Grouping;
+
+/// Parse kubectl-style memory size string in MiB
+/// Future improvements: support for other units, proper error handling.
+fn parse_mem(s: &str) -> u64 {
+    s.trim_end_matches("Mi").parse().unwrap_or_default()
+}
+
+/// Parse kubectl-style CPU quantity string in millicores
+fn parse_cpu(s: &str) -> u64 {
+    match s.strip_suffix('m') {
+        Some(m) => m.parse().unwrap_or_default(),
+        None => s.parse::<f64>().map(|c| c * 1000.0).unwrap_or_default() as u64,
+    }
+}
+
+/// Per-installation counters and resource usage totals
+#[derive(Debug)]
+pub struct InstallationCounters {
+    /// name of this installation
+    pub name: String,
+    /// group these counters are for within the installation, e.g. old/new
+    /// to designate which instance of Prometheus the data is from
+    pub group: String,
+    /// name of the container these counters are for, only set when a
+    /// per-container breakdown was requested
+    pub container: Option<String>,
+    /// name of the pod these counters are for, only set for groups that get a
+    /// row per pod
+    pub pod: Option<String>,
+    /// number of shards in this installation
+    pub shard_count: u32,
+    /// sum of CPU requests from all shards, in millicores
+    pub cpu_requests_total: u64,
+    /// sum of memory requests from all shards
+    pub mem_requests_total: u64,
+    /// sum of memory limits from all shards
+    pub mem_limits_total: u64,
+    /// sum of memory utilisation from all shards
+    pub mem_util_total: u64,
+}
+
+impl InstallationCounters {
+    fn new(name: &str, group: &str, container: Option<&str>) -> Self {
+        InstallationCounters {
+            name: name.to_owned(),
+            group: group.to_owned(),
+            container: container.map(String::from),
+            pod: None,
+            shard_count: 0,
+            cpu_requests_total: 0,
+            mem_requests_total: 0,
+            mem_limits_total: 0,
+            mem_util_total: 0,
+        }
+    }
+
+    /// Add resource figures from a split line, where `offset` is the index of
+    /// the memory requests column
+    fn add_resources(&mut self, s: &[&str], offset: usize) {
+        self.cpu_requests_total += parse_cpu(s[offset - 6]);
+        self.mem_requests_total += parse_mem(s[offset]);
+        self.mem_limits_total += parse_mem(s[offset + 2]);
+        self.mem_util_total += parse_mem(s[offset + 4]);
+    }
+
+    /// Construct counters for the same group and container as `self`, but a
+    /// different installation, with all totals zeroed
+    pub fn empty_like(&self, name: &str) -> Self {
+        InstallationCounters::new(name, &self.group, self.container.as_deref())
+    }
+
+    /// Add totals from other counters to these
+    pub fn add(&mut self, other: &InstallationCounters) {
+        self.shard_count += other.shard_count;
+        self.cpu_requests_total += other.cpu_requests_total;
+        self.mem_requests_total += other.mem_requests_total;
+        self.mem_limits_total += other.mem_limits_total;
+        self.mem_util_total += other.mem_util_total;
+    }
+}
+
+/// Options controlling how utilisation data is attributed to rows
+#[derive(Debug)]
+pub struct ParseOptions {
+    /// how pods are grouped into rows within a section
+    pub grouping: Grouping,
+    /// produce a separate row for each container instead of one per pod
+    pub by_container: bool,
+    /// containers (e.g. sidecars) left out of the totals
+    pub exclude_containers: Vec<String>,
+}
+
+/// Implements iteration over utilisation data
+///
+/// Lines are pulled from the underlying reader one at a time and rows are
+/// emitted as soon as the section they belong to is complete, so memory use
+/// is bounded by the size of a section rather than the whole input.
+pub struct UtilisationData<R: BufRead> {
+    data: Peekable<Lines<R>>,
+    options: ParseOptions,
+    /// whether the current section has a CONTAINER column, i.e. it was
+    /// captured with `kubectl resource-capacity --containers`
+    has_container: bool,
+    /// rows from the last completed section that weren't returned yet
+    pending: VecDeque<InstallationCounters>,
+}
+
+impl<R: BufRead> UtilisationData<R> {
+    /// Construct UtilisationData from a buffered reader
+    pub fn from(reader: R, options: ParseOptions) -> Self {
+        UtilisationData {
+            data: reader.lines().peekable(),
+            options,
+            has_container: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Check if the next line starts a new section, without consuming it
+    fn at_section_header(&mut self) -> bool {
+        matches!(self.data.peek(), Some(Ok(nxt)) if nxt.starts_with("# "))
+    }
+
+    /// Read lines up to the end of the next section and queue up its rows,
+    /// returns false once input is exhausted
+    fn read_section(&mut self) -> Result<bool> {
+        let mut name = String::new();
+        if self.at_section_header() {
+            // first line in the section should contain the installation name
+            let line = self.data.next().unwrap()?;
+            name.push_str(line.trim_start_matches("# "));
+        } else if self.data.peek().is_none() {
+            // end of input
+            return Ok(false);
+        }
+
+        let mut rows: Vec<InstallationCounters> = Vec::new();
+        while let Some(line) = self.data.next() {
+            let line = line.context("Could not read line")?;
+            let s: Vec<_> = line.split_whitespace().collect();
+
+            if s.first() == Some(&"NODE") {
+                // column headers, tell us if there's a CONTAINER column
+                self.has_container = s.contains(&"CONTAINER");
+            } else if s.len() > 2 && s[1] != "*" {
+                // lines with `*` in namespace column are node or cluster
+                // totals rather than pods
+                if let Some(group) = self.options.grouping.group_for(s[1], s[2])
+                {
+                    self.add_line(&name, &group, &s, &mut rows);
+                }
+            }
+
+            if self.at_section_header() {
+                // we reached the end of current section, break out
+                break;
+            }
+        }
+
+        let grouping = &self.options.grouping;
+        if !self.options.by_container {
+            for group in grouping.fixed_groups() {
+                row_for(&mut rows, &name, group, None, None);
+            }
+        }
+        rows.sort_by_key(|r| grouping.rank(&r.group));
+        self.pending.extend(rows);
+        Ok(true)
+    }
+
+    /// Attribute resources from a split pod or container line to a row
+    fn add_line(
+        &self,
+        name: &str,
+        group: &str,
+        s: &[&str],
+        rows: &mut Vec<InstallationCounters>,
+    ) {
+        let offset = if self.has_container { 10 } else { 9 };
+        if s.len() < offset + 6 {
+            // should not happen but if it does it's not a useful line
+            return;
+        }
+        let pod = Some(s[2]).filter(|_| self.options.grouping.per_pod(group));
+
+        if !self.has_container {
+            let row = row_for(rows, name, group, None, pod);
+            row.shard_count += 1;
+            row.add_resources(s, offset);
+            return;
+        }
+
+        match s[3] {
+            // pod totals; resources are taken from container lines instead so
+            // that sidecars can be left out
+            "*" if !self.options.by_container => {
+                row_for(rows, name, group, None, pod).shard_count += 1
+            }
+            "*" => (),
+            c if self.options.exclude_containers.iter().any(|e| e == c) => (),
+            c if self.options.by_container => {
+                let row = row_for(rows, name, group, Some(c), pod);
+                row.shard_count += 1;
+                row.add_resources(s, offset);
+            }
+            _ => row_for(rows, name, group, None, pod).add_resources(s, offset),
+        }
+    }
+}
+
+/// Find the row for a group, container and pod, adding it if there isn't one
+/// yet
+fn row_for<'a>(
+    rows: &'a mut Vec<InstallationCounters>,
+    name: &str,
+    group: &str,
+    container: Option<&str>,
+    pod: Option<&str>,
+) -> &'a mut InstallationCounters {
+    let pos = rows
+        .iter()
+        .position(|r| {
+            r.group == group
+                && r.container.as_deref() == container
+                && r.pod.as_deref() == pod
+        })
+        .unwrap_or_else(|| {
+            let mut row = InstallationCounters::new(name, group, container);
+            row.pod = pod.map(String::from);
+            rows.push(row);
+            rows.len() - 1
+        });
+    &mut rows[pos]
+}
+
+impl<R: BufRead> Iterator for UtilisationData<R> {
+    type Item = Result<InstallationCounters>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.read_section() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
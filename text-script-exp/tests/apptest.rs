@@ -29,6 +29,22 @@ fn it_outputs_a_summary() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn it_keeps_old_and_new_rows_during_migration() -> Result<()> {
+    let mut cmd = command()?;
+    // an installation with two old pods and no shards, and one with both
+    let fpath = resource("prometheus.resource-capacity.migration.txt");
+
+    cmd.arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.migration.old-new-comparison.txt",
+        )));
+
+    Ok(())
+}
+
 #[test]
 fn file_doesnt_exist() -> Result<()> {
     let mut cmd = command()?;
@@ -42,3 +58,293 @@ fn file_doesnt_exist() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn it_streams_a_summary() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.util.txt");
+    let expected = std::fs::read_to_string(resource(
+        "prometheus.resource-capacity.old-new-comparison.txt",
+    ))?;
+
+    cmd.arg("--stream").arg(&fpath);
+    let output = cmd.assert().success().get_output().stdout.clone();
+
+    // rows are aligned independently in streaming mode, so only compare the
+    // cells in each row
+    let cells = |s: &str| -> Vec<Vec<String>> {
+        s.lines()
+            .map(|l| l.split_whitespace().map(String::from).collect())
+            .collect()
+    };
+    assert_eq!(cells(&String::from_utf8(output)?), cells(&expected));
+
+    Ok(())
+}
+
+#[test]
+fn it_reads_from_stdin() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.util.txt");
+
+    cmd.pipe_stdin(&fpath)?;
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.old-new-comparison.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn it_reads_compressed_files() -> Result<()> {
+    for ext in &["gz", "zst"] {
+        let mut cmd = command()?;
+        let fpath =
+            resource(&format!("prometheus.resource-capacity.util.txt.{}", ext));
+
+        cmd.arg(&fpath);
+        cmd.assert()
+            .success()
+            .stdout(predicate::path::eq_file(resource(
+                "prometheus.resource-capacity.old-new-comparison.txt",
+            )));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_detects_compressed_stdin() -> Result<()> {
+    for ext in &["gz", "zst"] {
+        let mut cmd = command()?;
+        let fpath =
+            resource(&format!("prometheus.resource-capacity.util.txt.{}", ext));
+
+        cmd.pipe_stdin(&fpath)?;
+        cmd.assert()
+            .success()
+            .stdout(predicate::path::eq_file(resource(
+                "prometheus.resource-capacity.old-new-comparison.txt",
+            )));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn it_detects_mislabelled_compression() -> Result<()> {
+    let mut cmd = command()?;
+    // zstd compressed, despite the extension
+    let fpath = resource("prometheus.resource-capacity.util.mislabelled.gz");
+
+    cmd.arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.old-new-comparison.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn it_sums_containers_of_a_pod() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.containers.summary.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn it_excludes_sidecars() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.args(["--exclude-container", "thanos-sidecar"])
+        .args(["--exclude-container", "config-reloader"])
+        .arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.containers.no-sidecars.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn it_breaks_down_by_container() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.arg("--by-container").arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.containers.by-container.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn it_groups_by_section() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.args(["--group-by", "section"]).arg(&fpath);
+    cmd.assert().success().stdout(predicate::eq(
+        "INSTALLATION  PODS  REQUESTS  LIMITS  UTIL
+okapi         3     16022Mi   5732Mi  12179Mi
+quokka        2     16740Mi   4402Mi  13601Mi
+",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn it_groups_by_pod_name() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.args(["--group-by", "pod"])
+        .args(["--pod-regex", "^prometheus-([a-z0-9]{5})-0$"])
+        .args(["--group-column", "SHARD"])
+        .arg(&fpath);
+    cmd.assert().success().stdout(predicate::eq(
+        "INSTALLATION  SHARD  PODS  REQUESTS  LIMITS  UTIL
+okapi         a1b2c  1     2354Mi    2354Mi  1670Mi
+okapi         d3e4f  1     3378Mi    3378Mi  2368Mi
+quokka        g5h6i  1     4402Mi    4402Mi  3782Mi
+",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn pod_regex_without_capture_group() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.args(["--group-by", "pod", "--pod-regex", "prometheus"])
+        .arg(&fpath);
+    cmd.assert().code(2).stderr(
+        predicate::str::contains("Invalid arguments")
+            .and(predicate::str::contains("no capture group")),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn options_for_another_grouping() -> Result<()> {
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+    for (args, option) in &[
+        (
+            &["--namespace-group", "new=-prometheus$"][..],
+            "--namespace-group",
+        ),
+        (
+            &["--group-by", "section", "--pod-regex", "(.*)"][..],
+            "--pod-regex",
+        ),
+        (
+            &["--group-by", "section", "--group-column", "X"][..],
+            "--group-column",
+        ),
+    ] {
+        let mut cmd = command()?;
+        cmd.args(*args).arg(&fpath);
+        cmd.assert().code(2).stderr(
+            predicate::str::contains("Invalid arguments")
+                .and(predicate::str::contains(*option)),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn invalid_arguments_before_input() -> Result<()> {
+    let mut cmd = command()?;
+
+    cmd.args(["--group-by", "section", "--pod-regex", "(.*)"])
+        .arg("file/doesnt/exist");
+    cmd.assert().code(2).stderr(
+        predicate::str::contains("Invalid arguments")
+            .and(predicate::str::contains("Could not read input").not()),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unknown_grouping() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.args(["--group-by", "namespaces"]).arg(&fpath);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("isn't a valid value"));
+
+    Ok(())
+}
+
+#[test]
+fn it_estimates_costs() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.arg("--prices").arg(resource("prices.toml")).arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.containers.costs.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn it_estimates_costs_by_region() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.arg("--prices")
+        .arg(resource("prices.regions.toml"))
+        .arg(&fpath);
+    cmd.assert()
+        .success()
+        .stdout(predicate::path::eq_file(resource(
+            "prometheus.resource-capacity.containers.costs.txt",
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn installation_with_region_and_prices() -> Result<()> {
+    let mut cmd = command()?;
+    let fpath = resource("prometheus.resource-capacity.containers.txt");
+
+    cmd.arg("--prices")
+        .arg(resource("prices.ambiguous.toml"))
+        .arg(&fpath);
+    cmd.assert()
+        .code(1)
+        .stderr(predicate::str::contains("Invalid price table"));
+
+    Ok(())
+}